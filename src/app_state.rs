@@ -1,24 +1,51 @@
+use crate::github::client::GitHubClient;
+use crate::github::tokens::TokenCache;
+use crate::nur::cancel::BuildRegistry;
+use crate::nur::queue::BuildQueue;
 use jsonwebtoken::EncodingKey;
 use reqwest::Client;
 use std::env;
 
 pub struct AppState {
-    pub client: Client,
+    pub github: GitHubClient,
     pub encoding_key: EncodingKey,
     pub app_id: String,
-    pub webhook_secret: String,
+    pub installation_tokens: TokenCache,
+    /// Every secret a delivery may be signed with, see `verify_signature`.
+    pub webhook_secrets: Vec<String>,
+    pub build_queue: BuildQueue,
+    pub builds_in_flight: BuildRegistry,
+    /// How long a delivery id is remembered to drop GitHub redeliveries.
+    pub dedup_window: chrono::Duration,
 }
 
-pub fn build_app_state() -> Result<AppState, Box<dyn std::error::Error>> {
+pub fn build_app_state(build_queue: BuildQueue) -> Result<AppState, Box<dyn std::error::Error>> {
     let app_id = env::var("APP_ID")?;
-    let webhook_secret = env::var("WEBHOOK_SECRET")?;
+    // WEBHOOK_SECRETS takes a comma-separated list for rotations.
+    let webhook_secrets: Vec<String> = env::var("WEBHOOK_SECRETS")
+        .or_else(|_| env::var("WEBHOOK_SECRET"))?
+        .split(',')
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect();
+    if webhook_secrets.is_empty() {
+        return Err("No webhook secret configured".into());
+    }
+    let dedup_window_secs = env::var("WEBHOOK_DEDUP_WINDOW_SECS")
+        .ok()
+        .and_then(|s| s.parse::<i64>().ok())
+        .unwrap_or(24 * 60 * 60);
     let private_key_path = env::var("PRIVATE_KEY_PATH")?;
     let private_key = std::fs::read_to_string(&private_key_path)?;
 
     Ok(AppState {
-        client: Client::new(),
+        github: GitHubClient::from_env(Client::new()).with_app_id(&app_id),
         encoding_key: EncodingKey::from_rsa_pem(private_key.as_bytes())?,
         app_id,
-        webhook_secret,
+        installation_tokens: TokenCache::default(),
+        webhook_secrets,
+        build_queue,
+        builds_in_flight: BuildRegistry::default(),
+        dedup_window: chrono::Duration::seconds(dedup_window_secs),
     })
 }
@@ -1,13 +1,159 @@
+use crate::github::client::{GitHubClient, GitHubError};
+use crate::github::tokens::InstallationAuth;
+use reqwest::Method;
+use serde::de::IgnoredAny;
+use serde::{Deserialize, Serialize};
 use serde_json::json;
 
+/// GitHub accepts at most this many annotations per check run update.
+const MAX_ANNOTATIONS_PER_REQUEST: usize = 50;
+
+/// A message pinned to lines of a file in the repository.
+#[derive(Debug, Clone, Serialize)]
+pub struct Annotation {
+    pub path: String,
+    pub start_line: u64,
+    pub end_line: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub start_column: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub end_column: Option<u64>,
+    /// `notice`, `warning` or `failure`.
+    pub annotation_level: &'static str,
+    pub title: String,
+    pub message: String,
+}
+
+/// A button on a completed check run. Clicking it sends a `check_run`
+/// webhook with action `requested_action` and this identifier.
+#[derive(Debug, Clone, Serialize)]
+pub struct CheckRunAction {
+    /// At most 20 characters.
+    pub label: &'static str,
+    /// At most 40 characters.
+    pub description: &'static str,
+    /// At most 20 characters.
+    pub identifier: &'static str,
+}
+
+pub const REBUILD_ACTION: CheckRunAction = CheckRunAction {
+    label: "Rebuild",
+    description: "Build and deploy this commit again",
+    identifier: "rebuild",
+};
+
+pub const ROLLBACK_ACTION: CheckRunAction = CheckRunAction {
+    label: "Roll back",
+    description: "Rebuild and deploy the previous commit",
+    identifier: "rollback",
+};
+
+#[derive(Debug, Clone, Default)]
+pub struct CheckRunOutput {
+    pub summary: String,
+    /// Markdown shown under the summary, e.g. build logs. Empty to omit.
+    pub text: String,
+    pub annotations: Vec<Annotation>,
+    /// Only used when completing the check run.
+    pub actions: Vec<CheckRunAction>,
+}
+
+impl CheckRunOutput {
+    fn to_json(&self, annotations: &[Annotation]) -> serde_json::Value {
+        let mut output = json!({
+            "title": "Function Build",
+            "summary": self.summary,
+            "annotations": annotations,
+        });
+        if !self.text.is_empty() {
+            output["text"] = json!(self.text);
+        }
+        output
+    }
+}
+
+/// Everything needed to open and update check runs on one commit.
+#[derive(Clone)]
+pub struct CommitChecks {
+    pub github: GitHubClient,
+    pub auth: InstallationAuth,
+    pub owner: String,
+    pub repo: String,
+    pub head_sha: String,
+}
+
+impl CommitChecks {
+    /// A token valid right now; fetch one per request rather than keeping it.
+    pub async fn token(&self) -> Result<String, GitHubError> {
+        self.auth.token().await
+    }
+
+    pub async fn create(&self, name: &str) -> Result<u64, GitHubError> {
+        create_check_run(
+            &self.github,
+            &self.token().await?,
+            &self.owner,
+            &self.repo,
+            name,
+            &self.head_sha,
+        )
+        .await
+    }
+
+    pub async fn complete(
+        &self,
+        check_run_id: u64,
+        conclusion: &str,
+        output: &CheckRunOutput,
+    ) -> Result<(), GitHubError> {
+        complete_check_run(
+            &self.github,
+            &self.token().await?,
+            &self.owner,
+            &self.repo,
+            check_run_id,
+            conclusion,
+            output,
+        )
+        .await
+    }
+
+    pub async fn update_output(
+        &self,
+        check_run_id: u64,
+        output: &CheckRunOutput,
+    ) -> Result<(), GitHubError> {
+        update_check_run_output(
+            &self.github,
+            &self.token().await?,
+            &self.owner,
+            &self.repo,
+            check_run_id,
+            output,
+        )
+        .await
+    }
+}
+
+#[derive(Deserialize)]
+struct CheckRun {
+    id: u64,
+}
+
+#[derive(Deserialize)]
+struct CheckRunStatus {
+    status: String,
+}
+
 pub async fn create_check_run(
+    github: &GitHubClient,
     token: &str,
     owner: &str,
     repo: &str,
     name: &str,
     head_sha: &str,
-) -> Result<u64, String> {
-    let url = format!("https://api.github.com/repos/{}/{}/check-runs", owner, repo);
+) -> Result<u64, GitHubError> {
+    let path = format!("/repos/{}/{}/check-runs", owner, repo);
 
     let body = json!({
         "name": name,
@@ -16,56 +162,87 @@ pub async fn create_check_run(
         "started_at": chrono::Utc::now().to_rfc3339(),
     });
 
-    let client = reqwest::Client::new();
-    let res = client
-        .post(&url)
-        .bearer_auth(token)
-        .header("Accept", "application/vnd.github+json")
-        .header("User-Agent", "nur-build")
-        .json(&body)
-        .send()
-        .await
-        .map_err(|e| e.to_string())?;
-
-    let text = res.text().await.map_err(|e| e.to_string())?;
-
-    let json: serde_json::Value = serde_json::from_str(&text).map_err(|e| e.to_string())?;
-    Ok(json["id"].as_u64().unwrap_or(0))
+    let check_run: CheckRun = github
+        .request(Method::POST, &path, token, Some(&body))
+        .await?;
+    Ok(check_run.id)
 }
 
 pub async fn complete_check_run(
+    github: &GitHubClient,
     token: &str,
     owner: &str,
     repo: &str,
     check_run_id: u64,
     conclusion: &str,
-    summary: &str,
-) -> Result<(), String> {
-    let url = format!(
-        "https://api.github.com/repos/{}/{}/check-runs/{}",
-        owner, repo, check_run_id
-    );
+    output: &CheckRunOutput,
+) -> Result<(), GitHubError> {
+    let path = format!("/repos/{}/{}/check-runs/{}", owner, repo, check_run_id);
+    let mut batches = output.annotations.chunks(MAX_ANNOTATIONS_PER_REQUEST);
+    let first_batch = batches.next().unwrap_or_default();
 
-    let body = json!({
+    let mut body = json!({
         "status": "completed",
         "conclusion": conclusion,
         "completed_at": chrono::Utc::now().to_rfc3339(),
-        "output": {
-            "title": "Function Build",
-            "summary": summary,
-        }
+        "output": output.to_json(first_batch),
     });
+    if !output.actions.is_empty() {
+        body["actions"] = json!(output.actions);
+    }
+    patch_check_run(github, &path, token, &body, first_batch).await?;
 
-    let client = reqwest::Client::new();
-    client
-        .patch(&url)
-        .bearer_auth(token)
-        .header("Accept", "application/vnd.github+json")
-        .header("User-Agent", "nur-build")
-        .json(&body)
-        .send()
-        .await
-        .map_err(|e| e.to_string())?;
+    // Annotations sent in later updates are appended to the earlier ones.
+    for batch in batches {
+        let body = json!({ "output": output.to_json(batch) });
+        patch_check_run(github, &path, token, &body, batch).await?;
+    }
+
+    Ok(())
+}
+
+/// Whether the check run still waits for a conclusion.
+pub async fn check_run_is_open(
+    github: &GitHubClient,
+    token: &str,
+    owner: &str,
+    repo: &str,
+    check_run_id: u64,
+) -> Result<bool, GitHubError> {
+    let path = format!("/repos/{}/{}/check-runs/{}", owner, repo, check_run_id);
 
+    let check_run: CheckRunStatus = github.request(Method::GET, &path, token, None).await?;
+    Ok(check_run.status != "completed")
+}
+
+/// Replaces the output of a check run that is still in progress.
+pub async fn update_check_run_output(
+    github: &GitHubClient,
+    token: &str,
+    owner: &str,
+    repo: &str,
+    check_run_id: u64,
+    output: &CheckRunOutput,
+) -> Result<(), GitHubError> {
+    let path = format!("/repos/{}/{}/check-runs/{}", owner, repo, check_run_id);
+    let body = json!({ "output": output.to_json(&output.annotations) });
+
+    patch_check_run(github, &path, token, &body, &output.annotations).await
+}
+
+/// GitHub appends annotations instead of replacing them, so an update
+/// carrying some is not retried on a 5xx: it may have been applied already.
+async fn patch_check_run(
+    github: &GitHubClient,
+    path: &str,
+    token: &str,
+    body: &serde_json::Value,
+    annotations: &[Annotation],
+) -> Result<(), GitHubError> {
+    let _: IgnoredAny = if annotations.is_empty() {
+        github.patch_overwrite(path, token, body).await?
+    } else {
+        github.request(Method::PATCH, path, token, Some(body)).await?
+    };
     Ok(())
 }
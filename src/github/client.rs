@@ -0,0 +1,241 @@
+use reqwest::{Method, StatusCode};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::fmt;
+use std::time::Duration;
+
+const DEFAULT_API_URL: &str = "https://api.github.com";
+const MAX_ATTEMPTS: u32 = 3;
+
+#[derive(Debug)]
+pub enum GitHubError {
+    /// The request never got a response (DNS, TLS, connection reset...).
+    Request(reqwest::Error),
+    /// GitHub answered with a non-2xx status.
+    Status { status: StatusCode, body: String },
+    /// Still rate limited after every retry.
+    RateLimited { body: String },
+    /// A 2xx response whose body didn't have the expected shape.
+    Decode(String),
+    /// A download went past the size it was allowed to have.
+    TooLarge { limit: u64 },
+    /// No installation token could be obtained for the request.
+    Auth(String),
+}
+
+impl fmt::Display for GitHubError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GitHubError::Request(e) => write!(f, "GitHub request failed: {}", e),
+            GitHubError::Status { status, body } => write!(f, "GitHub returned {}: {}", status, body),
+            GitHubError::RateLimited { body } => write!(f, "GitHub rate limit hit: {}", body),
+            GitHubError::Decode(e) => write!(f, "Unexpected GitHub response: {}", e),
+            GitHubError::TooLarge { limit } => {
+                write!(f, "GitHub download is larger than {} bytes", limit)
+            }
+            GitHubError::Auth(e) => write!(f, "No GitHub installation token: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for GitHubError {}
+
+/// Thin wrapper around the GitHub REST API. Cloning it is cheap and shares
+/// the underlying connection pool.
+#[derive(Clone)]
+pub struct GitHubClient {
+    http: reqwest::Client,
+    base_url: String,
+    app_id: Option<u64>,
+}
+
+impl GitHubClient {
+    /// `GITHUB_API_URL` points the client at GitHub Enterprise Server
+    /// (`https://<host>/api/v3`) or a local fake; defaults to github.com.
+    pub fn from_env(http: reqwest::Client) -> Self {
+        let base_url =
+            std::env::var("GITHUB_API_URL").unwrap_or_else(|_| DEFAULT_API_URL.to_string());
+
+        GitHubClient {
+            http,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            app_id: None,
+        }
+    }
+
+    /// The app this client acts for, to recognize what it wrote before.
+    pub fn with_app_id(mut self, app_id: &str) -> Self {
+        self.app_id = app_id.trim().parse().ok();
+        self
+    }
+
+    pub fn app_id(&self) -> Option<u64> {
+        self.app_id
+    }
+
+    /// Sends a request authenticated with `token` (an installation token or
+    /// the app JWT) and decodes the JSON response. Rate limits and failed
+    /// connections are retried with backoff, as GitHub never saw or applied
+    /// the request; 5xx responses only for idempotent methods, since a POST
+    /// or PATCH may have been applied before the error and would be
+    /// duplicated.
+    pub async fn request<T: DeserializeOwned>(
+        &self,
+        method: Method,
+        path: &str,
+        token: &str,
+        body: Option<&Value>,
+    ) -> Result<T, GitHubError> {
+        let replayable = is_idempotent(&method);
+        self.send(method, path, token, body, replayable).await
+    }
+
+    /// A PATCH that only overwrites fields with the values in `body`, so it
+    /// is safe to replay after a 5xx. Not for anything GitHub appends, like
+    /// check run annotations.
+    pub async fn patch_overwrite<T: DeserializeOwned>(
+        &self,
+        path: &str,
+        token: &str,
+        body: &Value,
+    ) -> Result<T, GitHubError> {
+        self.send(Method::PATCH, path, token, Some(body), true).await
+    }
+
+    async fn send<T: DeserializeOwned>(
+        &self,
+        method: Method,
+        path: &str,
+        token: &str,
+        body: Option<&Value>,
+        replayable: bool,
+    ) -> Result<T, GitHubError> {
+        let url = format!("{}{}", self.base_url, path);
+        let mut attempt = 1;
+
+        loop {
+            let mut req = self
+                .http
+                .request(method.clone(), &url)
+                .bearer_auth(token)
+                .header("Accept", "application/vnd.github+json")
+                .header("User-Agent", "nur-build");
+            if let Some(body) = body {
+                req = req.json(body);
+            }
+
+            let res = match req.send().await {
+                Ok(res) => res,
+                Err(e) if e.is_connect() && attempt < MAX_ATTEMPTS => {
+                    let wait = Duration::from_secs(1 << attempt);
+                    println!("⏳ GitHub {} {} could not connect, retrying in {:?}", method, path, wait);
+                    tokio::time::sleep(wait).await;
+                    attempt += 1;
+                    continue;
+                }
+                Err(e) => return Err(GitHubError::Request(e)),
+            };
+            let status = res.status();
+            let retry_after = retry_after(&res);
+            let text = res.text().await.map_err(GitHubError::Request)?;
+
+            if status.is_success() {
+                let text = if text.is_empty() { "null" } else { text.as_str() };
+                return serde_json::from_str(text).map_err(|e| GitHubError::Decode(e.to_string()));
+            }
+
+            let rate_limited = is_rate_limited(status, retry_after.is_some(), &text);
+            let retryable = rate_limited || (status.is_server_error() && replayable);
+            if retryable && attempt < MAX_ATTEMPTS {
+                let wait = retry_after.unwrap_or(Duration::from_secs(1 << attempt));
+                println!(
+                    "⏳ GitHub {} {} returned {}, retrying in {:?}",
+                    method, path, status, wait
+                );
+                tokio::time::sleep(wait).await;
+                attempt += 1;
+                continue;
+            }
+
+            if rate_limited {
+                return Err(GitHubError::RateLimited { body: text });
+            }
+            return Err(GitHubError::Status { status, body: text });
+        }
+    }
+
+    /// Downloads a binary response such as a tarball, following GitHub's
+    /// redirect to its download host, and gives up once more than
+    /// `max_bytes` have arrived.
+    pub async fn download(
+        &self,
+        path: &str,
+        token: &str,
+        max_bytes: u64,
+    ) -> Result<Vec<u8>, GitHubError> {
+        let url = format!("{}{}", self.base_url, path);
+
+        let mut res = self
+            .http
+            .get(&url)
+            .bearer_auth(token)
+            .header("Accept", "application/vnd.github+json")
+            .header("User-Agent", "nur-build")
+            .send()
+            .await
+            .map_err(GitHubError::Request)?;
+
+        let status = res.status();
+        if !status.is_success() {
+            let body = res.text().await.map_err(GitHubError::Request)?;
+            return Err(GitHubError::Status { status, body });
+        }
+        if res.content_length().is_some_and(|len| len > max_bytes) {
+            return Err(GitHubError::TooLarge { limit: max_bytes });
+        }
+
+        let mut bytes = Vec::new();
+        while let Some(chunk) = res.chunk().await.map_err(GitHubError::Request)? {
+            if (bytes.len() + chunk.len()) as u64 > max_bytes {
+                return Err(GitHubError::TooLarge { limit: max_bytes });
+            }
+            bytes.extend_from_slice(&chunk);
+        }
+        Ok(bytes)
+    }
+}
+
+fn retry_after(res: &reqwest::Response) -> Option<Duration> {
+    let headers = res.headers();
+
+    if let Some(secs) = headers
+        .get("retry-after")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok())
+    {
+        return Some(Duration::from_secs(secs));
+    }
+
+    // Primary limits only tell us when the window resets.
+    if headers.get("x-ratelimit-remaining").and_then(|v| v.to_str().ok()) == Some("0") {
+        let reset = headers
+            .get("x-ratelimit-reset")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<i64>().ok())?;
+        let wait = reset - chrono::Utc::now().timestamp();
+        return Some(Duration::from_secs(wait.clamp(1, 60) as u64));
+    }
+
+    None
+}
+
+/// PATCH is left out: what it does depends on the resource, e.g. check run
+/// annotations are appended. Callers opt in with `patch_overwrite`.
+fn is_idempotent(method: &Method) -> bool {
+    matches!(*method, Method::GET | Method::HEAD | Method::PUT | Method::DELETE)
+}
+
+fn is_rate_limited(status: StatusCode, has_retry_after: bool, body: &str) -> bool {
+    (status == StatusCode::FORBIDDEN || status == StatusCode::TOO_MANY_REQUESTS)
+        && (has_retry_after || body.contains("rate limit"))
+}
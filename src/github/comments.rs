@@ -0,0 +1,131 @@
+use crate::github::client::{GitHubClient, GitHubError};
+use reqwest::Method;
+use serde::de::IgnoredAny;
+use serde::Deserialize;
+use serde_json::json;
+
+/// Comments are listed this many at a time, GitHub's maximum.
+const COMMENTS_PER_PAGE: usize = 100;
+/// Stop looking for our comment after this many pages.
+const MAX_COMMENT_PAGES: usize = 30;
+
+#[derive(Deserialize)]
+struct IssueComment {
+    id: u64,
+    #[serde(default)]
+    body: String,
+    user: Option<CommentUser>,
+    performed_via_github_app: Option<CommentApp>,
+}
+
+#[derive(Deserialize)]
+struct CommentUser {
+    #[serde(rename = "type")]
+    kind: String,
+}
+
+#[derive(Deserialize)]
+struct CommentApp {
+    id: u64,
+}
+
+impl IssueComment {
+    /// Only our app's bot can have written it: anyone can type the marker.
+    fn posted_by(&self, app_id: Option<u64>) -> bool {
+        let is_bot = self.user.as_ref().is_some_and(|user| user.kind == "Bot");
+        let app = self.performed_via_github_app.as_ref().map(|app| app.id);
+        is_bot && app.is_some() && app == app_id
+    }
+}
+
+pub async fn create_issue_comment(
+    github: &GitHubClient,
+    token: &str,
+    owner: &str,
+    repo: &str,
+    issue_number: u64,
+    body: &str,
+) -> Result<u64, GitHubError> {
+    let path = format!("/repos/{}/{}/issues/{}/comments", owner, repo, issue_number);
+
+    let comment: IssueComment = github
+        .request(Method::POST, &path, token, Some(&json!({ "body": body })))
+        .await?;
+    Ok(comment.id)
+}
+
+/// Keeps a single comment per issue: the first comment posted by this app
+/// containing `marker` is edited in place, otherwise a new one is posted.
+/// `body` must contain `marker` for the next call to find it.
+pub async fn upsert_issue_comment(
+    github: &GitHubClient,
+    token: &str,
+    owner: &str,
+    repo: &str,
+    issue_number: u64,
+    marker: &str,
+    body: &str,
+) -> Result<u64, GitHubError> {
+    let Some(existing) = find_app_comment(github, token, owner, repo, issue_number, marker).await?
+    else {
+        return create_issue_comment(github, token, owner, repo, issue_number, body).await;
+    };
+
+    let path = format!("/repos/{}/{}/issues/comments/{}", owner, repo, existing);
+    let _: IgnoredAny = github
+        .patch_overwrite(&path, token, &json!({ "body": body }))
+        .await?;
+    Ok(existing)
+}
+
+async fn find_app_comment(
+    github: &GitHubClient,
+    token: &str,
+    owner: &str,
+    repo: &str,
+    issue_number: u64,
+    marker: &str,
+) -> Result<Option<u64>, GitHubError> {
+    for page in 1..=MAX_COMMENT_PAGES {
+        let path = format!(
+            "/repos/{}/{}/issues/{}/comments?per_page={}&page={}",
+            owner, repo, issue_number, COMMENTS_PER_PAGE, page
+        );
+        let comments: Vec<IssueComment> = github.request(Method::GET, &path, token, None).await?;
+
+        if let Some(existing) = comments
+            .iter()
+            .find(|c| c.posted_by(github.app_id()) && c.body.contains(marker))
+        {
+            return Ok(Some(existing.id));
+        }
+        if comments.len() < COMMENTS_PER_PAGE {
+            break;
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn comment(user_type: &str, app_id: Option<u64>) -> IssueComment {
+        serde_json::from_value(json!({
+            "id": 1,
+            "body": "<!-- marker -->",
+            "user": { "login": "someone", "type": user_type },
+            "performed_via_github_app": app_id.map(|id| json!({ "id": id })),
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn only_comments_by_our_app_are_reused() {
+        assert!(comment("Bot", Some(42)).posted_by(Some(42)));
+        assert!(!comment("Bot", Some(7)).posted_by(Some(42)));
+        assert!(!comment("Bot", None).posted_by(Some(42)));
+        assert!(!comment("User", Some(42)).posted_by(Some(42)));
+        assert!(!comment("Bot", None).posted_by(None));
+    }
+}
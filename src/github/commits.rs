@@ -0,0 +1,44 @@
+use crate::github::client::{GitHubClient, GitHubError};
+use reqwest::Method;
+use serde::Deserialize;
+
+/// The compare API lists at most this many files; past it the list is
+/// incomplete.
+const MAX_COMPARE_FILES: usize = 300;
+
+#[derive(Deserialize)]
+struct Comparison {
+    #[serde(default)]
+    files: Vec<ComparedFile>,
+}
+
+#[derive(Deserialize)]
+struct ComparedFile {
+    filename: String,
+    previous_filename: Option<String>,
+}
+
+/// Paths changed between two commits, counting both sides of a rename.
+/// `None` when GitHub truncated the list.
+pub async fn compare_changed_files(
+    github: &GitHubClient,
+    token: &str,
+    owner: &str,
+    repo: &str,
+    base: &str,
+    head: &str,
+) -> Result<Option<Vec<String>>, GitHubError> {
+    let path = format!("/repos/{}/{}/compare/{}...{}", owner, repo, base, head);
+    let comparison: Comparison = github.request(Method::GET, &path, token, None).await?;
+
+    if comparison.files.len() >= MAX_COMPARE_FILES {
+        return Ok(None);
+    }
+
+    let mut paths = Vec::with_capacity(comparison.files.len());
+    for file in comparison.files {
+        paths.extend(file.previous_filename);
+        paths.push(file.filename);
+    }
+    Ok(Some(paths))
+}
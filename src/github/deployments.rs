@@ -0,0 +1,177 @@
+use crate::github::checks::CommitChecks;
+use crate::github::client::{GitHubClient, GitHubError};
+use crate::github::tokens::InstallationAuth;
+use reqwest::Method;
+use serde::de::IgnoredAny;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+/// GitHub rejects longer descriptions instead of truncating them.
+const MAX_DESCRIPTION_CHARS: usize = 140;
+
+/// What to record about a deploy of one commit to one environment.
+pub struct DeploymentRequest {
+    /// `production`, or `preview/pr-<n>` for pull request previews.
+    pub environment: String,
+    pub description: String,
+    pub payload: Value,
+    pub production: bool,
+}
+
+/// A deployment that was created on GitHub and can still receive statuses.
+#[derive(Clone)]
+pub struct Deployment {
+    pub github: GitHubClient,
+    pub auth: InstallationAuth,
+    pub owner: String,
+    pub repo: String,
+    pub id: u64,
+}
+
+impl Deployment {
+    /// Opens a deployment for the commit `checks` reports on.
+    pub async fn create(
+        checks: &CommitChecks,
+        request: &DeploymentRequest,
+    ) -> Result<Deployment, GitHubError> {
+        let id = create_deployment(
+            &checks.github,
+            &checks.token().await?,
+            &checks.owner,
+            &checks.repo,
+            &checks.head_sha,
+            request,
+        )
+        .await?;
+
+        Ok(Deployment {
+            github: checks.github.clone(),
+            auth: checks.auth.clone(),
+            owner: checks.owner.clone(),
+            repo: checks.repo.clone(),
+            id,
+        })
+    }
+
+    /// `state` is one of `in_progress`, `success`, `failure`, `error` or
+    /// `inactive`.
+    pub async fn set_status(&self, state: &str, description: &str) -> Result<(), GitHubError> {
+        create_deployment_status(
+            &self.github,
+            &self.auth.token().await?,
+            &self.owner,
+            &self.repo,
+            self.id,
+            state,
+            description,
+        )
+        .await
+    }
+}
+
+#[derive(Deserialize)]
+struct CreatedDeployment {
+    id: u64,
+}
+
+#[derive(Deserialize)]
+struct DeploymentStatus {
+    state: String,
+}
+
+pub async fn create_deployment(
+    github: &GitHubClient,
+    token: &str,
+    owner: &str,
+    repo: &str,
+    head_sha: &str,
+    request: &DeploymentRequest,
+) -> Result<u64, GitHubError> {
+    let path = format!("/repos/{}/{}/deployments", owner, repo);
+
+    // Our own check runs are still in progress at this point, so commit
+    // statuses must not gate the deployment, and the SHA must not be merged
+    // with the default branch.
+    let body = json!({
+        "ref": head_sha,
+        "environment": request.environment,
+        "description": truncate(&request.description),
+        "payload": request.payload,
+        "auto_merge": false,
+        "required_contexts": [],
+        "production_environment": request.production,
+        "transient_environment": !request.production,
+    });
+
+    let deployment: CreatedDeployment = github
+        .request(Method::POST, &path, token, Some(&body))
+        .await?;
+    Ok(deployment.id)
+}
+
+pub async fn create_deployment_status(
+    github: &GitHubClient,
+    token: &str,
+    owner: &str,
+    repo: &str,
+    deployment_id: u64,
+    state: &str,
+    description: &str,
+) -> Result<(), GitHubError> {
+    let path = format!(
+        "/repos/{}/{}/deployments/{}/statuses",
+        owner, repo, deployment_id
+    );
+
+    let body = json!({
+        "state": state,
+        "description": truncate(description),
+    });
+
+    let _: IgnoredAny = github
+        .request(Method::POST, &path, token, Some(&body))
+        .await?;
+    Ok(())
+}
+
+/// The newest deployment to `environment`, if there ever was one.
+pub async fn latest_deployment_id(
+    github: &GitHubClient,
+    token: &str,
+    owner: &str,
+    repo: &str,
+    environment: &str,
+) -> Result<Option<u64>, GitHubError> {
+    // Deployments are listed newest first.
+    let path = format!(
+        "/repos/{}/{}/deployments?environment={}&per_page=1",
+        owner,
+        repo,
+        environment.replace('/', "%2F")
+    );
+
+    let deployments: Vec<CreatedDeployment> = github.request(Method::GET, &path, token, None).await?;
+    Ok(deployments.into_iter().next().map(|deployment| deployment.id))
+}
+
+/// The state of the deployment's newest status, `None` before the first.
+pub async fn latest_deployment_state(
+    github: &GitHubClient,
+    token: &str,
+    owner: &str,
+    repo: &str,
+    deployment_id: u64,
+) -> Result<Option<String>, GitHubError> {
+    // Statuses are listed newest first.
+    let path = format!(
+        "/repos/{}/{}/deployments/{}/statuses?per_page=1",
+        owner, repo, deployment_id
+    );
+
+    let statuses: Vec<DeploymentStatus> = github.request(Method::GET, &path, token, None).await?;
+    Ok(statuses.into_iter().next().map(|status| status.state))
+}
+
+fn truncate(description: &str) -> String {
+    description.chars().take(MAX_DESCRIPTION_CHARS).collect()
+}
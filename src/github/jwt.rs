@@ -3,15 +3,16 @@ use std::time::{SystemTime, UNIX_EPOCH};
 
 use crate::github::models::Claims;
 
-pub fn create_jwt(app_id: &str, key: &EncodingKey) -> String {
+pub fn create_jwt(app_id: &str, key: &EncodingKey) -> Result<String, String> {
     let now = SystemTime::now()
         .duration_since(UNIX_EPOCH)
-        .unwrap()
+        .map_err(|e| e.to_string())?
         .as_secs() as usize;
     let claims = Claims {
         iat: now,
         exp: now + 600,
         iss: app_id.to_string(),
     };
-    encode(&Header::new(Algorithm::RS256), &claims, key).unwrap()
+    encode(&Header::new(Algorithm::RS256), &claims, key)
+        .map_err(|e| format!("Failed to sign app JWT: {}", e))
 }
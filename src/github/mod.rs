@@ -1,3 +1,9 @@
 pub mod jwt;
 pub mod models;
 pub mod checks;
+pub mod client;
+pub mod comments;
+pub mod commits;
+pub mod deployments;
+pub mod repos;
+pub mod tokens;
@@ -1,13 +1,209 @@
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 
+/// A webhook delivery, typed by its `x-github-event` header.
+#[derive(Debug)]
+pub enum WebhookEvent {
+    Push(GitHubPushEvent),
+    PullRequest(GitHubPullRequestEvent),
+    CheckRun(GitHubCheckRunEvent),
+    CheckSuite(GitHubCheckSuiteEvent),
+    Installation(GitHubInstallationEvent),
+    InstallationRepositories(GitHubInstallationRepositoriesEvent),
+    Repository(GitHubRepositoryEvent),
+    Delete(GitHubDeleteEvent),
+}
+
+impl WebhookEvent {
+    /// `Ok(None)` for event types we don't handle, so they can be
+    /// acknowledged without being parsed.
+    pub fn parse(event_type: &str, body: &[u8]) -> Result<Option<WebhookEvent>, serde_json::Error> {
+        fn parse<T: DeserializeOwned>(body: &[u8]) -> Result<T, serde_json::Error> {
+            serde_json::from_slice(body)
+        }
+
+        let event = match event_type {
+            "push" => WebhookEvent::Push(parse(body)?),
+            "pull_request" => WebhookEvent::PullRequest(parse(body)?),
+            "check_run" => WebhookEvent::CheckRun(parse(body)?),
+            "check_suite" => WebhookEvent::CheckSuite(parse(body)?),
+            "installation" => WebhookEvent::Installation(parse(body)?),
+            "installation_repositories" => WebhookEvent::InstallationRepositories(parse(body)?),
+            "repository" => WebhookEvent::Repository(parse(body)?),
+            "delete" => WebhookEvent::Delete(parse(body)?),
+            _ => return Ok(None),
+        };
+        Ok(Some(event))
+    }
+}
+
 #[derive(Deserialize, Serialize, Debug)]
 pub struct GitHubPushEvent {
+    #[serde(rename = "ref")]
+    pub git_ref: String,
     pub before: String,
+    /// All zeros when the push deleted the ref.
     pub after: String,
+    /// `None` when the push deleted the ref.
+    pub head_commit: Option<PushCommit>,
+    /// Up to 20 commits, oldest first. Longer pushes need the compare API.
+    #[serde(default)]
+    pub commits: Vec<PushCommit>,
+    pub pusher: Pusher,
+    #[serde(default)]
+    pub created: bool,
+    #[serde(default)]
+    pub deleted: bool,
+    #[serde(default)]
+    pub forced: bool,
+    pub repository: Repository,
+    pub installation: Installation,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct PushCommit {
+    pub id: String,
+    pub message: String,
+    pub timestamp: String,
+    #[serde(default)]
+    pub added: Vec<String>,
+    #[serde(default)]
+    pub removed: Vec<String>,
+    #[serde(default)]
+    pub modified: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct Pusher {
+    pub name: String,
+    pub email: Option<String>,
+}
+
+impl GitHubPushEvent {
+    /// Deleting a branch or tag is also delivered as a push, with nothing
+    /// to build.
+    pub fn is_deletion(&self) -> bool {
+        self.deleted || self.after.chars().all(|c| c == '0')
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct GitHubPullRequestEvent {
+    pub action: String,
+    pub number: u64,
+    pub pull_request: PullRequest,
+    pub repository: Repository,
+    pub installation: Installation,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct PullRequest {
+    pub number: u64,
+    pub head: PullRequestRef,
+    pub base: PullRequestRef,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct PullRequestRef {
+    #[serde(rename = "ref")]
+    pub git_ref: String,
+    pub sha: String,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct GitHubCheckRunEvent {
+    pub action: String,
+    pub check_run: CheckRun,
+    /// Only on `requested_action`.
+    pub requested_action: Option<RequestedAction>,
+    pub repository: Repository,
+    pub installation: Installation,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct GitHubCheckSuiteEvent {
+    pub action: String,
+    pub check_suite: CheckSuite,
+    pub repository: Repository,
+    pub installation: Installation,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct CheckRun {
+    pub id: u64,
+    pub name: String,
+    pub head_sha: String,
+    pub check_suite: CheckSuite,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct CheckSuite {
+    pub id: u64,
+    pub head_sha: String,
+    /// For a fork's suite this is the fork's branch, e.g. `main`, not one
+    /// of this repository's, and `pull_requests` is empty.
+    pub head_branch: Option<String>,
+    #[serde(default)]
+    pub pull_requests: Vec<CheckSuitePullRequest>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct CheckSuitePullRequest {
+    pub number: u64,
+    pub head: PullRequestRef,
+    pub base: PullRequestRef,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct RequestedAction {
+    pub identifier: String,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct GitHubRepositoryEvent {
+    pub action: String,
     pub repository: Repository,
     pub installation: Installation,
 }
 
+#[derive(Deserialize, Serialize, Debug)]
+pub struct GitHubDeleteEvent {
+    /// The short name, e.g. `feature/x`, without `refs/heads/`.
+    #[serde(rename = "ref")]
+    pub git_ref: String,
+    /// `branch` or `tag`.
+    pub ref_type: String,
+    pub repository: Repository,
+    pub installation: Installation,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct GitHubInstallationEvent {
+    pub action: String,
+    pub installation: Installation,
+    /// Only on `created`.
+    #[serde(default)]
+    pub repositories: Vec<InstallationRepository>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct GitHubInstallationRepositoriesEvent {
+    pub action: String,
+    pub installation: Installation,
+    #[serde(default)]
+    pub repositories_added: Vec<InstallationRepository>,
+    #[serde(default)]
+    pub repositories_removed: Vec<InstallationRepository>,
+}
+
+/// The short repository form used by installation events.
+#[derive(Deserialize, Serialize, Debug)]
+pub struct InstallationRepository {
+    pub id: u64,
+    pub name: String,
+    pub full_name: String,
+}
+
 #[derive(Deserialize, Serialize, Debug)]
 pub struct Repository {
     pub id: u64,
@@ -15,12 +211,16 @@ pub struct Repository {
     pub full_name: String,
     pub private: bool,
     pub clone_url: String,
+    pub default_branch: String,
     pub owner: RepositoryOwner,
 }
 
 #[derive(Deserialize, Serialize, Debug)]
 pub struct RepositoryOwner {
-    pub name: String,
+    /// The account handle, always present. Use this for API paths.
+    pub login: String,
+    /// Only sent on push events, and only for user-owned repositories.
+    pub name: Option<String>,
 }
 
 #[derive(Deserialize, Serialize, Debug)]
@@ -34,3 +234,177 @@ pub struct Claims {
     pub exp: usize,
     pub iss: String,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_fixture(event_type: &str, fixture: &str) -> WebhookEvent {
+        WebhookEvent::parse(event_type, fixture.as_bytes())
+            .expect("fixture should deserialize")
+            .expect("event type should be handled")
+    }
+
+    #[test]
+    fn push() {
+        let WebhookEvent::Push(event) = parse_fixture("push", include_str!("fixtures/push.json")) else {
+            panic!("expected a push event");
+        };
+
+        assert_eq!(event.git_ref, "refs/heads/main");
+        assert_eq!(event.after, "6113728f27ae82c7b1a177c8d03f9e96e0adf246");
+        assert!(event.forced);
+        assert!(!event.is_deletion());
+        assert_eq!(event.pusher.name, "Codertocat");
+        assert_eq!(event.commits.len(), 2);
+        assert_eq!(event.commits[0].added, ["functions/hello/src/lib.rs", "functions/hello/Cargo.toml"]);
+        assert_eq!(event.commits[1].removed, ["functions/old/main.go"]);
+        assert_eq!(event.head_commit.unwrap().id, event.after);
+        assert_eq!(event.repository.owner.login, "Codertocat");
+        assert_eq!(event.repository.owner.name.as_deref(), Some("Codertocat"));
+        assert_eq!(event.installation.id, 2311213);
+    }
+
+    #[test]
+    fn push_deleting_a_branch() {
+        let WebhookEvent::Push(event) =
+            parse_fixture("push", include_str!("fixtures/push_deleted.json"))
+        else {
+            panic!("expected a push event");
+        };
+
+        assert!(event.deleted);
+        assert!(event.is_deletion());
+        assert!(event.head_commit.is_none());
+        assert!(event.commits.is_empty());
+    }
+
+    #[test]
+    fn pull_request_on_an_organization_repository() {
+        let WebhookEvent::PullRequest(event) =
+            parse_fixture("pull_request", include_str!("fixtures/pull_request_opened.json"))
+        else {
+            panic!("expected a pull_request event");
+        };
+
+        assert_eq!(event.action, "opened");
+        assert_eq!(event.number, 2);
+        assert_eq!(event.pull_request.head.git_ref, "feature/greeting");
+        assert_eq!(event.pull_request.base.git_ref, "main");
+        // Organization owners come without a `name` outside of push events.
+        assert_eq!(event.repository.owner.login, "nur-org");
+        assert_eq!(event.repository.owner.name, None);
+    }
+
+    #[test]
+    fn check_run_requested_action() {
+        let WebhookEvent::CheckRun(event) = parse_fixture(
+            "check_run",
+            include_str!("fixtures/check_run_requested_action.json"),
+        ) else {
+            panic!("expected a check_run event");
+        };
+
+        assert_eq!(event.action, "requested_action");
+        assert_eq!(event.requested_action.unwrap().identifier, "rollback");
+        assert_eq!(event.check_run.name, "Nur functions compilation");
+        assert_eq!(event.check_run.check_suite.head_branch.as_deref(), Some("main"));
+    }
+
+    #[test]
+    fn check_suite_from_a_pull_request() {
+        let WebhookEvent::CheckSuite(event) = parse_fixture(
+            "check_suite",
+            include_str!("fixtures/check_suite_rerequested.json"),
+        ) else {
+            panic!("expected a check_suite event");
+        };
+
+        assert_eq!(event.action, "rerequested");
+        assert_eq!(event.check_suite.head_branch.as_deref(), Some("feature/greeting"));
+        let pr = &event.check_suite.pull_requests[0];
+        assert_eq!(pr.number, 2);
+        assert_eq!(pr.head.git_ref, "feature/greeting");
+        assert_eq!(pr.base.git_ref, "main");
+    }
+
+    #[test]
+    fn check_suite_from_a_fork() {
+        let WebhookEvent::CheckSuite(event) = parse_fixture(
+            "check_suite",
+            include_str!("fixtures/check_suite_rerequested_fork.json"),
+        ) else {
+            panic!("expected a check_suite event");
+        };
+
+        // The fork's own branch name, with no pull request to tell it apart
+        // from this repository's `main`.
+        assert_eq!(event.action, "rerequested");
+        assert_eq!(event.check_suite.head_branch.as_deref(), Some("main"));
+        assert!(event.check_suite.pull_requests.is_empty());
+    }
+
+    #[test]
+    fn installation_created() {
+        let WebhookEvent::Installation(event) = parse_fixture(
+            "installation",
+            include_str!("fixtures/installation_created.json"),
+        ) else {
+            panic!("expected an installation event");
+        };
+
+        assert_eq!(event.action, "created");
+        assert_eq!(event.installation.id, 2311213);
+        let names: Vec<&str> = event.repositories.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, ["Hello-World", "functions"]);
+    }
+
+    #[test]
+    fn installation_repositories_removed() {
+        let WebhookEvent::InstallationRepositories(event) = parse_fixture(
+            "installation_repositories",
+            include_str!("fixtures/installation_repositories_removed.json"),
+        ) else {
+            panic!("expected an installation_repositories event");
+        };
+
+        assert_eq!(event.action, "removed");
+        assert!(event.repositories_added.is_empty());
+        assert_eq!(event.repositories_removed[0].id, 186853007);
+    }
+
+    #[test]
+    fn repository_renamed() {
+        let WebhookEvent::Repository(event) =
+            parse_fixture("repository", include_str!("fixtures/repository_renamed.json"))
+        else {
+            panic!("expected a repository event");
+        };
+
+        assert_eq!(event.action, "renamed");
+        assert_eq!(event.repository.id, 186853002);
+        assert_eq!(event.repository.name, "hello-nur");
+    }
+
+    #[test]
+    fn delete_branch() {
+        let WebhookEvent::Delete(event) =
+            parse_fixture("delete", include_str!("fixtures/delete_branch.json"))
+        else {
+            panic!("expected a delete event");
+        };
+
+        assert_eq!(event.git_ref, "feature/greeting");
+        assert_eq!(event.ref_type, "branch");
+    }
+
+    #[test]
+    fn unhandled_event_types_are_not_parsed() {
+        assert!(WebhookEvent::parse("star", b"not even json").unwrap().is_none());
+    }
+
+    #[test]
+    fn malformed_payloads_are_errors() {
+        assert!(WebhookEvent::parse("push", br#"{"ref": "refs/heads/main"}"#).is_err());
+    }
+}
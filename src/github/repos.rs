@@ -0,0 +1,49 @@
+use crate::github::client::{GitHubClient, GitHubError};
+use reqwest::Method;
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+struct RepositoryInfo {
+    default_branch: String,
+}
+
+#[derive(Deserialize)]
+struct GitRef {
+    object: GitObject,
+}
+
+#[derive(Deserialize)]
+struct GitObject {
+    sha: String,
+}
+
+pub async fn get_default_branch(
+    github: &GitHubClient,
+    token: &str,
+    owner: &str,
+    repo: &str,
+) -> Result<String, GitHubError> {
+    let path = format!("/repos/{}/{}", owner, repo);
+    let info: RepositoryInfo = github.request(Method::GET, &path, token, None).await?;
+    Ok(info.default_branch)
+}
+
+/// The commit `branch` points to in the repository itself, `None` when it
+/// has no such branch.
+pub async fn get_branch_head(
+    github: &GitHubClient,
+    token: &str,
+    owner: &str,
+    repo: &str,
+    branch: &str,
+) -> Result<Option<String>, GitHubError> {
+    let path = format!("/repos/{}/{}/git/ref/heads/{}", owner, repo, branch);
+
+    match github.request::<GitRef>(Method::GET, &path, token, None).await {
+        Ok(git_ref) => Ok(Some(git_ref.object.sha)),
+        Err(GitHubError::Status { status, .. }) if status == reqwest::StatusCode::NOT_FOUND => {
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
@@ -0,0 +1,98 @@
+use crate::app_state::AppState;
+use crate::github::client::GitHubError;
+use crate::github::jwt::create_jwt;
+use chrono::{DateTime, Duration, Utc};
+use reqwest::Method;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// Tokens are refreshed this long before GitHub says they expire, so a
+/// token handed out is still good for the request it was asked for. Long
+/// builds don't hold on to one: they ask `InstallationAuth` before each call.
+const REFRESH_MARGIN_MINUTES: i64 = 5;
+
+#[derive(Deserialize, Clone)]
+struct InstallationToken {
+    token: String,
+    expires_at: DateTime<Utc>,
+}
+
+/// Installation access tokens by installation id. Each installation has
+/// its own lock, so a slow exchange for one doesn't hold up the others.
+#[derive(Default)]
+pub struct TokenCache {
+    tokens: Mutex<HashMap<u64, Arc<tokio::sync::Mutex<Option<InstallationToken>>>>>,
+}
+
+impl TokenCache {
+    fn slot(&self, installation_id: u64) -> Arc<tokio::sync::Mutex<Option<InstallationToken>>> {
+        let mut tokens = self.tokens.lock().unwrap_or_else(|e| e.into_inner());
+        tokens.entry(installation_id).or_default().clone()
+    }
+}
+
+/// Gets a valid token for one installation whenever one is needed, so a
+/// build outliving its first token keeps working.
+#[derive(Clone)]
+pub struct InstallationAuth {
+    state: Arc<AppState>,
+    installation_id: u64,
+}
+
+impl InstallationAuth {
+    pub fn new(state: Arc<AppState>, installation_id: u64) -> Self {
+        InstallationAuth {
+            state,
+            installation_id,
+        }
+    }
+
+    pub async fn token(&self) -> Result<String, GitHubError> {
+        get_installation_token(&self.state, self.installation_id)
+            .await
+            .map_err(GitHubError::Auth)
+    }
+}
+
+/// Returns a cached installation token, exchanging the app JWT for a new
+/// one when there is none or it is about to expire.
+pub async fn get_installation_token(state: &AppState, installation_id: u64) -> Result<String, String> {
+    let slot = state.installation_tokens.slot(installation_id);
+    // Held across the exchange so concurrent builds for the same
+    // installation don't all request a fresh token at once.
+    let mut cached_token = slot.lock().await;
+
+    if let Some(cached) = cached_token.as_ref() {
+        if cached.expires_at - Duration::minutes(REFRESH_MARGIN_MINUTES) > Utc::now() {
+            return Ok(cached.token.clone());
+        }
+    }
+
+    let fresh = request_installation_token(state, installation_id).await?;
+    println!(
+        "🔑 New token for installation {} (expires at {})",
+        installation_id, fresh.expires_at
+    );
+    *cached_token = Some(fresh.clone());
+
+    Ok(fresh.token)
+}
+
+async fn request_installation_token(
+    state: &AppState,
+    installation_id: u64,
+) -> Result<InstallationToken, String> {
+    let jwt = create_jwt(&state.app_id, &state.encoding_key)?;
+
+    state
+        .github
+        .request(
+            Method::POST,
+            &format!("/app/installations/{}/access_tokens", installation_id),
+            &jwt,
+            None,
+        )
+        .await
+        .map_err(|e| format!("Token exchange for installation {} failed: {}", installation_id, e))
+}
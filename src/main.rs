@@ -12,8 +12,10 @@ use tokio::process::Command;
 use std::sync::Arc;
 use tokio::net::TcpListener;
 use tokio::signal;
+use tokio::sync::mpsc;
 
 use crate::app_state::build_app_state;
+use crate::nur::queue::{recover_orphaned_jobs, spawn_build_workers};
 use crate::routes::supabase_test::supabase_route;
 use crate::routes::webhook_handler::webhook_handler;
 
@@ -29,13 +31,25 @@ async fn main() {
 
     tokio::spawn(let_the_shit_fail());
 
-    let app_state = build_app_state().expect("Failed to build AppState");
+    let (build_queue, build_receiver) = mpsc::unbounded_channel();
+    let app_state = Arc::new(build_app_state(build_queue).expect("Failed to build AppState"));
+
+    let workers = std::env::var("BUILD_WORKERS")
+        .ok()
+        .and_then(|w| w.parse::<usize>().ok())
+        .unwrap_or(2);
+    spawn_build_workers(app_state.clone(), build_receiver, workers);
+    println!("👷 Started {} build workers", workers);
+
+    if let Err(e) = recover_orphaned_jobs(&app_state).await {
+        println!("⚠️ Failed to recover orphaned build jobs: {}", e);
+    }
 
     let app = Router::new()
         .route("/webhook", post(webhook_handler))
         .route("/supabase-test", get(supabase_route))
         .route("/", get(|| async { "Hola Nur!!!" }))
-        .with_state(Arc::new(app_state));
+        .with_state(app_state);
 
     println!("Listening on http://0.0.0.0:3000");
 
@@ -1,135 +1,263 @@
-use crate::nur::config::{NurFile, NurFunction};
-use crate::nur::container_spawn::build_and_deploy_function;
+use crate::github::checks::{CheckRunOutput, CommitChecks};
+use crate::github::deployments::{Deployment, DeploymentRequest};
+use crate::github::repos::get_default_branch;
+use crate::nur::build_cache::hash_function_inputs;
+use crate::nur::cancel::CancelToken;
+use crate::nur::changes::{last_deployed_commits, split_by_changes};
+use crate::nur::config::{BranchRule, NurFile, NurFunction};
+use crate::nur::container_spawn::{
+    build_and_deploy_function, DeployTarget, FunctionArtifact, FunctionBuildContext,
+};
+use crate::nur::queue::{BuildJob, BuildKind};
+use crate::nur::report::{format_bytes, BuildReport};
+use crate::nur::source::{source_fetcher, SourceRequest};
 use crate::supabase::crud::{
-    get_build_id, get_project_id, get_supabase_client, insert_if_not_exists, insert_project_build,
+    find_project_id, get_function_id, get_project_source_fetch, get_supabase_client,
+    insert_if_not_exists, insert_project_build, update_build_job,
 };
+use postgrest::Postgrest;
+use serde_json::{json, Value};
+use std::collections::HashMap;
 use std::error::Error;
 use std::path::Path;
-use tokio::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use uuid::Uuid;
 
+pub enum BuildOutcome {
+    /// Every function was built and deployed to `target`.
+    Built {
+        functions: Vec<NurFunction>,
+        /// Functions the push didn't touch, left as they were.
+        skipped: Vec<NurFunction>,
+        target: DeployTarget,
+        artifacts: Vec<FunctionArtifact>,
+    },
+    /// The nurfile says this branch should not be built at all.
+    Skipped(String),
+}
+
 pub async fn run_nur_build(
-    clone_url: &str,
-    repo_id: &u64,
-) -> Result<Vec<NurFunction>, Box<dyn std::error::Error>> {
+    job: &BuildJob,
+    cancel: CancelToken,
+    report: BuildReport,
+    checks: CommitChecks,
+) -> Result<BuildOutcome, Box<dyn std::error::Error>> {
+    let head_sha = job.head_sha.as_str();
+    let git_ref = job.git_ref.as_str();
+
     let tmp_dir = format!("nur-{}", Uuid::new_v4());
     let tmp_path = std::env::current_dir().unwrap().join(&tmp_dir);
     let tmp_path_str = tmp_path.to_str().unwrap().to_string();
     tokio::fs::create_dir_all(&tmp_path_str).await?;
 
     let client = get_supabase_client().map_err(|e| format!("Supabase error: {}", e))?;
-    let repo_id_str = repo_id.to_string();
-    let project_id = get_project_id(&client, &repo_id_str).await?;
+    let repo_id_str = job.repo_id.to_string();
+    let Some(project_id) = find_project_id(&client, &repo_id_str).await? else {
+        return Err(not_linked_message(job).into());
+    };
 
     println!("🔗 Found Supabase project with ID: {}", project_id);
 
-    println!("📥 Cloning repo into: {}", tmp_path_str);
-    let output = Command::new("git")
-        .args(["clone", "--depth=1", clone_url, &tmp_path_str])
-        .output()
-        .await?;
-
-    if !output.status.success() {
-        println!(
-            "❌ Git clone failed:\n{}",
-            String::from_utf8_lossy(&output.stderr)
-        );
-        return Err("Git clone failed".into());
-    }
-
-    let (mut commit_hash, mut commit_msg, mut branchname) = (
-        "unknown".to_string(),
-        "no commit message".to_string(),
-        "unknown".to_string(),
-    );
-
-    let log_output = Command::new("git")
-        .args(["log", "-1", "--pretty=format:%H%n%s%n%D"])
-        .current_dir(&tmp_path_str)
-        .output()
-        .await?;
-
-    if log_output.status.success() {
-        let output_str = String::from_utf8_lossy(&log_output.stdout);
-        let mut lines = output_str.lines();
-        commit_hash = lines.next().unwrap_or("unknown").to_string();
-        commit_msg = lines.next().unwrap_or("no commit message").to_string();
-
-        let refs_line = lines.next().unwrap_or("");
-        if let Some(head_ref) = refs_line.split(',').find(|s| s.contains("HEAD ->")) {
-            if let Some(branch) = head_ref.split("->").nth(1) {
-                branchname = branch.trim().to_string();
-            }
+    let source_fetch = match get_project_source_fetch(&client, &project_id).await {
+        Ok(method) => method,
+        Err(e) => {
+            println!("⚠️ Could not read source_fetch, using git: {}", e);
+            None
         }
+    };
+    let fetcher = source_fetcher(source_fetch.as_deref(), &checks.github);
+
+    println!("📥 Fetching {} ({}) into: {}", head_sha, git_ref, tmp_path_str);
+    let token = checks.token().await?;
+    let request = SourceRequest {
+        owner: &job.owner,
+        repo: &job.repo_name,
+        clone_url: &job.clone_url,
+        head_sha,
+        token: &token,
+        dest: &tmp_path,
+    };
+    let commit = match fetcher.fetch(&request).await {
+        Ok(commit) => commit,
+        Err(e) => {
+            println!("❌ Source fetch failed:\n{}", e);
+            return Err(format!("Source fetch failed: {}", e).into());
+        }
+    };
+    let commit_hash = commit.sha;
+    let commit_msg = commit.message;
+
+    let branchname = git_ref
+        .strip_prefix("refs/heads/")
+        .unwrap_or(git_ref)
+        .to_string();
+
+    if commit_hash != head_sha {
+        return Err(format!(
+            "Checked out commit {} but the event asked for {}",
+            commit_hash, head_sha
+        )
+        .into());
+    }
 
-        println!("🔐 Last commit hash: {}", &commit_hash);
-        println!("📝 Commit message: {}", &commit_msg);
-        println!("🌿 Branch: {}", &branchname);
+    println!("🔐 Last commit hash: {}", &commit_hash);
+    println!("📝 Commit message: {}", &commit_msg);
+    println!("🌿 Branch: {}", &branchname);
+
+    if cancel.is_cancelled() {
+        return Err("Build cancelled before it started compiling".into());
     }
 
     let config_path = format!("{}/nurfile.yaml", tmp_path_str);
     let contents = tokio::fs::read_to_string(&config_path).await?;
     let config: NurFile = serde_yaml::from_str(&contents)?;
 
+    if !config.source.is_empty() {
+        if let Err(e) = fetcher.fetch_extras(&request, config.source).await {
+            println!("❌ Fetching submodules or LFS objects failed:\n{}", e);
+            return Err(format!("Fetching submodules or LFS objects failed: {}", e).into());
+        }
+    }
+
+    let default_branch = default_branch(job, &checks).await?;
+    let target = match &job.kind {
+        BuildKind::PullRequest { number, .. } if config.previews => {
+            DeployTarget::Preview { pr: *number }
+        }
+        BuildKind::PullRequest { .. } => DeployTarget::BuildOnly,
+        BuildKind::Push => match config.branch_rule(&branchname, &default_branch) {
+            BranchRule::Deploy => DeployTarget::Production,
+            BranchRule::BuildOnly => DeployTarget::BuildOnly,
+            BranchRule::Skip => {
+                println!("⏭️ Branch '{}' is not configured to build", branchname);
+                return Ok(BuildOutcome::Skipped(format!(
+                    "Branch `{}` does not match any `branches:` rule in nurfile.yaml",
+                    branchname
+                )));
+            }
+        },
+    };
+    println!("🚦 Deploy target: {:?}", target);
+
+    let (functions, skipped) =
+        select_functions(job, &checks, &client, &project_id, &target, config.functions).await;
+    report_skipped_functions(&checks, &skipped).await;
+
     let s3_bucket = std::env::var("S3_BUCKET")?;
 
     let builds_dir = Path::new(&tmp_path_str).join("builds");
     tokio::fs::create_dir_all(&builds_dir).await?;
 
-    let insert_result =
-        insert_project_build(&client, &project_id, &commit_hash, &branchname, &commit_msg).await;
-
-    match insert_result {
-        Ok(body) => println!("📬 Inserted build in Supabase: {}", body),
-        Err(e) => println!("❌ Failed to insert build in Supabase: {}", e),
-    }
+    let build_id =
+        insert_project_build(&client, &project_id, &commit_hash, &branchname, &commit_msg)
+            .await
+            .map_err(|e| format!("Failed to record the build in Supabase: {}", e))?;
+    println!("📬 Inserted build {} in Supabase", build_id);
 
-    let build_id = get_build_id(&client).await?;
+    // Hash before any container runs, while the checkout is pristine.
+    let input_hashes = hash_function_inputs(&functions, &tmp_path, &builds_dir).await;
 
-    println!("🔍 Found {} functions:", config.functions.len());
-    for func in &config.functions {
+    println!("🔍 Building {} function(s):", functions.len());
+    for func in &functions {
         println!("• {}", func.name);
+        report.progress(&func.name, "cloned");
         if let Err(e) = insert_if_not_exists(&client, &project_id, &func.name).await {
             println!("⚠️ Failed to insert '{}': {}", func.name, e);
         }
     }
 
-    let mut tasks = Vec::with_capacity(config.functions.len());
+    let deployment = open_deployment(
+        &checks,
+        &client,
+        &project_id,
+        &build_id,
+        &target,
+        &functions,
+    )
+    .await;
+    if let Some(deployment) = &deployment {
+        record_on_job(&client, job, json!({ "deployment_id": deployment.id })).await;
+    }
+    let check_run_ids = create_function_check_runs(&checks, &client, job, &functions).await;
+    let total = functions.len();
+    let deployed = Arc::new(AtomicUsize::new(0));
+
+    let mut tasks = Vec::with_capacity(total);
+    let build_cancel = cancel.clone();
 
-    let cloned_funcs = config.functions.clone();
-    for func in config.functions {
-        let tmp_path_str = tmp_path_str.clone();
-        let builds_dir = builds_dir.clone();
-        let client = get_supabase_client()?; // consider cloning if needed
-        let s3_bucket = s3_bucket.clone();
-        let project_id = project_id.clone();
-        let build_id = build_id.clone();
+    let ctx = FunctionBuildContext {
+        tmp_dir: tmp_path_str.clone(),
+        builds_dir,
+        client,
+        s3_bucket,
+        project_id,
+        build_id,
+        branch_name: branchname,
+        target: target.clone(),
+        job_id: job.id,
+        cancel,
+        report,
+        input_hash: None,
+        store_in_cache: matches!(job.kind, BuildKind::Push),
+    };
+
+    let cloned_funcs = functions.clone();
+    for func in functions {
+        let mut ctx = ctx.clone();
+        ctx.input_hash = input_hashes.get(&func.name).cloned();
+        let checks = checks.clone();
+        let deployment = deployment.clone();
+        let deployed = deployed.clone();
+        let check_run_id = check_run_ids.get(&func.name).copied();
 
         tasks.push(tokio::spawn(async move {
-            match build_and_deploy_function(
-                &func,
-                tmp_path_str,
-                builds_dir,
-                client,
-                s3_bucket,
-                project_id,
-                build_id,
-            )
-            .await
-            {
-                Ok(()) => Ok::<(), (String, Box<dyn Error + Send + Sync>)>(()),
-                Err(e) => Err((func.name.clone(), e)),
+            let report = ctx.report.clone();
+            let cancel = ctx.cancel.clone();
+
+            let started = Instant::now();
+            let result = build_and_deploy_function(&func, ctx).await;
+            match &result {
+                Ok(artifact) if artifact.key.is_some() => {
+                    let done = deployed.fetch_add(1, Ordering::SeqCst) + 1;
+                    if let Some(deployment) = &deployment {
+                        let description = format!("Deployed {} ({}/{})", func.name, done, total);
+                        if let Err(e) = deployment.set_status("in_progress", &description).await {
+                            println!("⚠️ Failed to update deployment {}: {}", deployment.id, e);
+                        }
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => report.progress(&func.name, &format!("failed: {}", e)),
+            }
+
+            if let Some(check_run_id) = check_run_id {
+                let (conclusion, summary) =
+                    function_check_summary(&result, started.elapsed(), cancel.is_cancelled());
+                let output = CheckRunOutput {
+                    summary,
+                    text: report.render_function_text(&func.name),
+                    annotations: report.function_diagnostics(&func.name),
+                    ..Default::default()
+                };
+                if let Err(e) = checks.complete(check_run_id, conclusion, &output).await {
+                    println!("⚠️ Failed to complete check run for '{}': {}", func.name, e);
+                }
             }
+
+            result.map_err(|e| (func.name.clone(), e))
         }));
     }
 
     let results = futures::future::try_join_all(tasks).await?;
 
     let mut failures = 0;
+    let mut artifacts = Vec::new();
 
     for result in results {
         match result {
-            Ok(_) => {} // build OK
+            Ok(artifact) => artifacts.push(artifact),
             Err((name, e)) => {
                 eprintln!("❌ Build failed for '{}': {}", name, e);
                 failures += 1;
@@ -137,10 +265,229 @@ pub async fn run_nur_build(
         }
     }
 
+    if let Some(deployment) = &deployment {
+        let (state, description) = if failures == 0 {
+            ("success", format!("Deployed {} function(s)", total))
+        } else if build_cancel.is_cancelled() {
+            ("error", "Superseded by a newer push".to_string())
+        } else {
+            ("failure", format!("{} of {} function(s) failed to build", failures, total))
+        };
+        if let Err(e) = deployment.set_status(state, &description).await {
+            println!("⚠️ Failed to update deployment {}: {}", deployment.id, e);
+        }
+    }
+
     if failures > 0 {
         return Err(format!("{} function(s) failed to build", failures).into());
     }
 
-    println!("✅ All functions built and deployed");
-    Ok(cloned_funcs)
+    if target == DeployTarget::BuildOnly {
+        println!("✅ All functions built (build-only, nothing deployed)");
+    } else {
+        println!("✅ All functions built and deployed");
+    }
+
+    Ok(BuildOutcome::Built {
+        functions: cloned_funcs,
+        skipped,
+        target,
+        artifacts,
+    })
+}
+
+/// Jobs queued before `default_branch` was recorded come back with an empty
+/// one; ask GitHub rather than treating no branch as the default.
+async fn default_branch(job: &BuildJob, checks: &CommitChecks) -> Result<String, String> {
+    if !job.default_branch.is_empty() {
+        return Ok(job.default_branch.clone());
+    }
+
+    let lookup = async {
+        let token = checks.token().await?;
+        get_default_branch(&checks.github, &token, &checks.owner, &checks.repo).await
+    };
+    lookup
+        .await
+        .map_err(|e| format!("Could not look up the default branch: {}", e))
+}
+
+/// Splits the nurfile's functions into those changed since what is deployed
+/// and the others. Only production pushes skip anything: pull requests,
+/// build-only branches, rebuilds and rollbacks build everything.
+async fn select_functions(
+    job: &BuildJob,
+    checks: &CommitChecks,
+    client: &Postgrest,
+    project_id: &str,
+    target: &DeployTarget,
+    functions: Vec<NurFunction>,
+) -> (Vec<NurFunction>, Vec<NurFunction>) {
+    if *target != DeployTarget::Production || job.before.is_none() {
+        return (functions, Vec::new());
+    }
+    let deployed = match last_deployed_commits(client, project_id).await {
+        Ok(deployed) => deployed,
+        Err(e) => {
+            println!("⚠️ Could not look up deployed commits, building everything: {}", e);
+            return (functions, Vec::new());
+        }
+    };
+
+    let (affected, unchanged) = split_by_changes(job, checks, &deployed, functions).await;
+    for func in &unchanged {
+        println!("⏭️ '{}' is unchanged, keeping its current deployment", func.name);
+    }
+    (affected, unchanged)
+}
+
+/// Unchanged functions still get their check run, so branch protection
+/// requiring it isn't left waiting.
+async fn report_skipped_functions(checks: &CommitChecks, skipped: &[NurFunction]) {
+    for func in skipped {
+        let result = async {
+            let check_run_id = checks.create(&format!("Nur function: {}", func.name)).await?;
+            let output = CheckRunOutput {
+                summary: format!(
+                    "No changes under `{}` or its `watch:` paths, the current deployment is kept.",
+                    func.directory
+                ),
+                ..Default::default()
+            };
+            checks.complete(check_run_id, "skipped", &output).await
+        }
+        .await;
+
+        if let Err(e) = result {
+            println!("⚠️ Failed to report skipped function '{}': {}", func.name, e);
+        }
+    }
+}
+
+/// One check run per function, so branch protection can require individual
+/// functions. They are all opened before anything builds and stored on the
+/// job, so a restart in the middle of the build can still close them.
+async fn create_function_check_runs(
+    checks: &CommitChecks,
+    client: &Postgrest,
+    job: &BuildJob,
+    functions: &[NurFunction],
+) -> HashMap<String, u64> {
+    let mut ids = HashMap::new();
+    for func in functions {
+        match checks.create(&format!("Nur function: {}", func.name)).await {
+            Ok(id) => {
+                ids.insert(func.name.clone(), id);
+            }
+            Err(e) => println!("⚠️ Failed to create check run for '{}': {}", func.name, e),
+        }
+    }
+
+    if !ids.is_empty() {
+        record_on_job(client, job, json!({ "function_check_run_ids": ids })).await;
+    }
+    ids
+}
+
+/// Stores what `recover_orphaned_jobs` needs to clean up after this build.
+async fn record_on_job(client: &Postgrest, job: &BuildJob, fields: Value) {
+    if let Err(e) = update_build_job(client, &job.id.to_string(), &fields).await {
+        println!("⚠️ Failed to record {} on build {}: {}", fields, job.id, e);
+    }
+}
+
+/// Records the deploy through GitHub's Deployments API so the repository's
+/// environments show what is live. Failing to do so is logged but never
+/// fails the build.
+async fn open_deployment(
+    checks: &CommitChecks,
+    client: &Postgrest,
+    project_id: &str,
+    build_id: &str,
+    target: &DeployTarget,
+    functions: &[NurFunction],
+) -> Option<Deployment> {
+    if functions.is_empty() {
+        return None;
+    }
+    let environment = target.environment()?;
+
+    let mut payload_functions = Vec::with_capacity(functions.len());
+    for func in functions {
+        let function_id = match get_function_id(client, project_id, &func.name).await {
+            Ok(id) => id,
+            Err(e) => {
+                println!("⚠️ Not recording a deployment, no ID for '{}': {}", func.name, e);
+                return None;
+            }
+        };
+        payload_functions.push(json!({
+            "name": func.name,
+            "function_id": function_id,
+            "artifact_key": target.artifact_key(&function_id),
+        }));
+    }
+
+    let request = DeploymentRequest {
+        description: format!("Nur build {}", build_id),
+        payload: json!({ "build_id": build_id, "functions": Value::Array(payload_functions) }),
+        production: *target == DeployTarget::Production,
+        environment,
+    };
+
+    let deployment = match Deployment::create(checks, &request).await {
+        Ok(deployment) => deployment,
+        Err(e) => {
+            println!("⚠️ Failed to create {} deployment: {}", request.environment, e);
+            return None;
+        }
+    };
+    println!("🚀 Created {} deployment {}", request.environment, deployment.id);
+
+    if let Err(e) = deployment.set_status("in_progress", "Building functions").await {
+        println!("⚠️ Failed to update deployment {}: {}", deployment.id, e);
+    }
+    Some(deployment)
+}
+
+fn not_linked_message(job: &BuildJob) -> String {
+    format!(
+        "`{owner}/{repo}` is not linked to a Nur project. Projects are created when the \
+         Nur GitHub App is installed on a repository: add `{repo}` to the app's repository \
+         access in your GitHub settings, or set `github_repo_id` to `{id}` on an existing \
+         project. Then push again or click Rebuild.",
+        owner = job.owner,
+        repo = job.repo_name,
+        id = job.repo_id,
+    )
+}
+
+fn function_check_summary(
+    result: &Result<FunctionArtifact, Box<dyn Error + Send + Sync>>,
+    elapsed: Duration,
+    cancelled: bool,
+) -> (&'static str, String) {
+    let secs = elapsed.as_secs_f64();
+
+    match result {
+        Ok(artifact) => {
+            let mut summary = format!(
+                "Built in {:.1}s. `{}.wasm`: {} ({} compressed).",
+                secs,
+                artifact.name,
+                format_bytes(artifact.wasm_size),
+                format_bytes(artifact.compressed_size)
+            );
+            match &artifact.key {
+                Some(key) => summary.push_str(&format!("\n\nDeployed to `{}`.", key)),
+                None => summary.push_str("\n\nNot deployed."),
+            }
+            ("success", summary)
+        }
+        Err(_) if cancelled => (
+            "cancelled",
+            format!("Cancelled after {:.1}s, superseded by a newer push.", secs),
+        ),
+        Err(e) => ("failure", format!("Failed after {:.1}s: {}", secs, e)),
+    }
 }
@@ -0,0 +1,262 @@
+use crate::nur::config::NurFunction;
+use crate::nur::container_spawn::builder_image;
+use crate::nur::upload_s3::{head_s3_object, upload_to_s3_with_metadata};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use tokio::process::Command;
+use walkdir::WalkDir;
+
+/// Bump to invalidate every cached artifact, e.g. when the hashed inputs
+/// change shape.
+const CACHE_VERSION: &str = "nur-build-cache-v2";
+
+/// S3 metadata holding the uncompressed size, so a cache hit can still
+/// report it without downloading the artifact.
+const WASM_SIZE_METADATA: &str = "wasm-size";
+
+/// An artifact built earlier from the same inputs.
+pub struct CachedArtifact {
+    pub key: String,
+    pub wasm_size: u64,
+    pub compressed_size: u64,
+}
+
+pub fn cache_key(hash: &str) -> String {
+    format!("cache/{}.wasm.zst", hash)
+}
+
+/// `NUR_BUILD_CACHE=off` makes every build run from scratch.
+pub fn cache_enabled() -> bool {
+    !matches!(
+        std::env::var("NUR_BUILD_CACHE").as_deref(),
+        Ok("off") | Ok("false") | Ok("0")
+    )
+}
+
+/// Content hash of each function's build inputs, by function name. The
+/// build container sees the whole checkout (workspace manifests, toolchain
+/// files, sibling crates...), so every file in it is an input. Functions
+/// whose builder image can't be resolved get no hash and are always built,
+/// and so does everything when the checkout can't be read.
+pub async fn hash_function_inputs(
+    functions: &[NurFunction],
+    workspace: &Path,
+    builds_dir: &Path,
+) -> HashMap<String, String> {
+    if !cache_enabled() {
+        return HashMap::new();
+    }
+
+    let mut images: HashMap<&'static str, Option<String>> = HashMap::new();
+    for func in functions {
+        let Some(image) = builder_image(&func.template) else {
+            continue;
+        };
+        if !images.contains_key(image) {
+            images.insert(image, image_id(image).await);
+        }
+    }
+
+    let workspace = workspace.to_path_buf();
+    let builds_dir = builds_dir.to_path_buf();
+    let tree = tokio::task::spawn_blocking(move || hash_tree(&workspace, &builds_dir))
+        .await
+        .map_err(std::io::Error::other)
+        .and_then(|tree| tree);
+    let tree = match tree {
+        Ok(tree) => tree,
+        Err(e) => {
+            println!("⚠️ Could not hash the checkout, building without cache: {}", e);
+            return HashMap::new();
+        }
+    };
+
+    functions
+        .iter()
+        .filter_map(|func| {
+            let image_id = images.get(builder_image(&func.template)?)?.as_deref()?;
+            Some((func.name.clone(), hash_function(func, image_id, &tree)))
+        })
+        .collect()
+}
+
+/// Looks up an artifact built from the same inputs.
+pub async fn cached_artifact(bucket: &str, hash: &str) -> Option<CachedArtifact> {
+    let key = cache_key(hash);
+    let object = match head_s3_object(bucket, &key).await {
+        Ok(object) => object?,
+        Err(e) => {
+            println!("⚠️ Build cache lookup for {} failed: {}", key, e);
+            return None;
+        }
+    };
+
+    // Without the uncompressed size the deployment can't be recorded
+    // properly, so treat it as a miss and let the build overwrite it.
+    let wasm_size = object.metadata.get(WASM_SIZE_METADATA)?.parse().ok()?;
+    Some(CachedArtifact {
+        key,
+        wasm_size,
+        compressed_size: object.size,
+    })
+}
+
+/// Stores a freshly built artifact under its input hash. Failing to do so
+/// only costs a rebuild next time, so it's logged and otherwise ignored.
+pub async fn store_artifact(bucket: &str, hash: &str, zip_path: &Path, wasm_size: u64) {
+    let key = cache_key(hash);
+    let metadata = HashMap::from([(WASM_SIZE_METADATA.to_string(), wasm_size.to_string())]);
+
+    let result = upload_to_s3_with_metadata(bucket, &key, zip_path, metadata)
+        .await
+        .map_err(|e| e.to_string());
+    if let Err(e) = result {
+        println!("⚠️ Could not store {} in the build cache: {}", key, e);
+    }
+}
+
+/// Image ID and registry digest of a builder image, pulling it first if
+/// it isn't there. A moving tag like `latest` then still invalidates the
+/// cache once a new image is pulled.
+async fn image_id(image: &str) -> Option<String> {
+    if let Some(id) = inspect_image(image).await {
+        return Some(id);
+    }
+
+    let pulled = Command::new("podman")
+        .args(["pull", "--quiet", image])
+        .output()
+        .await
+        .is_ok_and(|output| output.status.success());
+    if !pulled {
+        println!("⚠️ Could not resolve builder image '{}', building without cache", image);
+        return None;
+    }
+    inspect_image(image).await
+}
+
+async fn inspect_image(image: &str) -> Option<String> {
+    let output = Command::new("podman")
+        .args(["image", "inspect", "--format", "{{.Id}}@{{.Digest}}", image])
+        .output()
+        .await
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+
+    let id = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    (!id.is_empty()).then_some(id)
+}
+
+fn hash_function(func: &NurFunction, image_id: &str, tree: &str) -> String {
+    let mut hasher = Sha256::new();
+    for field in [
+        CACHE_VERSION,
+        tree,
+        image_id,
+        &func.template.to_lowercase(),
+        &func.directory,
+        &func.build.command,
+        &func.build.output,
+    ] {
+        hash_field(&mut hasher, field.as_bytes());
+    }
+    hex::encode(hasher.finalize())
+}
+
+/// Hash of every file in the checkout: path, executable bit and content,
+/// or target for symlinks. `.git` and the builder's own output are left out.
+fn hash_tree(workspace: &Path, builds_dir: &Path) -> std::io::Result<String> {
+    let mut hasher = Sha256::new();
+
+    let walker = WalkDir::new(workspace)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(|entry| entry.file_name() != ".git" && entry.path() != builds_dir);
+    for entry in walker {
+        let entry = entry.map_err(std::io::Error::other)?;
+        if entry.file_type().is_dir() {
+            continue;
+        }
+
+        let relative = entry.path().strip_prefix(workspace).unwrap_or(entry.path());
+        hash_field(&mut hasher, relative.to_string_lossy().as_bytes());
+
+        if entry.file_type().is_symlink() {
+            let target = std::fs::read_link(entry.path())?;
+            hash_field(&mut hasher, b"link");
+            hash_field(&mut hasher, target.to_string_lossy().as_bytes());
+        } else {
+            let mode = entry.metadata().map_err(std::io::Error::other)?.permissions().mode();
+            let executable = mode & 0o111 != 0;
+            hash_field(&mut hasher, if executable { b"exec" } else { b"file" });
+            hash_field(&mut hasher, &std::fs::read(entry.path())?);
+        }
+    }
+
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Length-prefixed, so moving bytes from one field to the next changes
+/// the hash.
+fn hash_field(hasher: &mut Sha256, bytes: &[u8]) {
+    hasher.update((bytes.len() as u64).to_le_bytes());
+    hasher.update(bytes);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(root: &Path, path: &str, content: &str) {
+        let path = root.join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, content).unwrap();
+    }
+
+    #[test]
+    fn any_file_in_the_checkout_changes_the_hash() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let builds = root.join("builds");
+        write(root, "functions/hello/src/lib.rs", "fn main() {}");
+        write(root, "functions/shared/src/lib.rs", "fn shared() {}");
+        let tree = || hash_tree(root, &builds).unwrap();
+
+        let original = tree();
+        assert_eq!(original, tree());
+
+        // The builder's output and git's own files don't count.
+        write(root, "builds/hello.wasm.zst", "artifact");
+        write(root, ".git/HEAD", "ref: refs/heads/main");
+        assert_eq!(original, tree());
+
+        for (path, content) in [
+            ("functions/shared/src/lib.rs", "fn changed() {}"),
+            ("Cargo.toml", "[workspace]"),
+            ("rust-toolchain.toml", "[toolchain]"),
+            (".cargo/config.toml", "[build]"),
+        ] {
+            let before = tree();
+            write(root, path, content);
+            assert_ne!(before, tree(), "{}", path);
+        }
+    }
+
+    #[test]
+    fn function_hash_covers_image_and_build_settings() {
+        let func = NurFunction::for_tests("functions/hello");
+        let original = hash_function(&func, "sha256:aaa", "tree");
+
+        assert_eq!(original, hash_function(&func, "sha256:aaa", "tree"));
+        assert_ne!(original, hash_function(&func, "sha256:bbb", "tree"));
+        assert_ne!(original, hash_function(&func, "sha256:aaa", "other tree"));
+
+        let mut release = func.clone();
+        release.build.command.push_str(" --release");
+        assert_ne!(original, hash_function(&release, "sha256:aaa", "tree"));
+    }
+}
@@ -0,0 +1,198 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tokio::sync::watch;
+use uuid::Uuid;
+
+/// Handed to a running build so it can notice it has been superseded.
+#[derive(Clone)]
+pub struct CancelToken(watch::Receiver<bool>);
+
+impl CancelToken {
+    pub fn is_cancelled(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    /// Resolves once the build is cancelled, never if it isn't.
+    pub async fn cancelled(&mut self) {
+        if self.0.wait_for(|cancelled| *cancelled).await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+struct InFlight {
+    build_id: Uuid,
+    /// The branch being built; for pull requests, their head branch.
+    git_ref: String,
+    cancel: watch::Sender<bool>,
+}
+
+/// Builds stopped by `BuildRegistry::cancel_branch` or `cancel_key`.
+pub struct Stopping(Vec<watch::Receiver<bool>>);
+
+impl Stopping {
+    /// Resolves once every one of them has finished, or was replaced by a
+    /// newer build: either way its entry, and so its sender, is dropped.
+    pub async fn finished(mut self) {
+        for receiver in &mut self.0 {
+            while receiver.changed().await.is_ok() {}
+        }
+    }
+}
+
+/// Tracks the newest build per repository and branch (or pull request), so
+/// that a newer push cancels whatever is still queued or running for the
+/// same key.
+#[derive(Default)]
+pub struct BuildRegistry {
+    builds: Mutex<HashMap<(u64, String), InFlight>>,
+}
+
+impl BuildRegistry {
+    /// Makes `build_id` the newest build for the key and cancels the
+    /// previous one, if any.
+    pub fn supersede(&self, repo_id: u64, key: &str, git_ref: &str, build_id: Uuid) {
+        let (cancel, _) = watch::channel(false);
+        let previous = self.builds.lock().unwrap().insert(
+            (repo_id, key.to_string()),
+            InFlight {
+                build_id,
+                git_ref: git_ref.to_string(),
+                cancel,
+            },
+        );
+
+        if let Some(previous) = previous {
+            println!("🛑 Build {} superseded by {}", previous.build_id, build_id);
+            previous.cancel.send_replace(true);
+        }
+    }
+
+    /// Cancels whatever is queued or running for `git_ref`, pushes and pull
+    /// requests from it alike, without starting anything new, e.g. because
+    /// the branch was deleted. The entries stay until those builds finish,
+    /// so a queued one still sees it was cancelled when it starts.
+    pub fn cancel_branch(&self, repo_id: u64, git_ref: &str) -> Stopping {
+        self.cancel_where(repo_id, git_ref, |_, build| build.git_ref == git_ref)
+    }
+
+    /// Like `cancel_branch`, for the build of a single key, e.g. a pull
+    /// request that was closed.
+    pub fn cancel_key(&self, repo_id: u64, key: &str) -> Stopping {
+        self.cancel_where(repo_id, key, |build_key, _| build_key == key)
+    }
+
+    fn cancel_where(
+        &self,
+        repo_id: u64,
+        reason: &str,
+        matches: impl Fn(&str, &InFlight) -> bool,
+    ) -> Stopping {
+        let builds = self.builds.lock().unwrap();
+
+        let stopping = builds
+            .iter()
+            .filter(|((repo, key), build)| *repo == repo_id && matches(key, build))
+            .map(|((_, key), build)| {
+                println!("🛑 Build {} ({}) cancelled, {} is gone", build.build_id, key, reason);
+                build.cancel.send_replace(true);
+                build.cancel.subscribe()
+            })
+            .collect();
+        Stopping(stopping)
+    }
+
+    pub fn is_in_flight(&self, repo_id: u64, key: &str) -> bool {
+        self.builds
+            .lock()
+            .unwrap()
+            .contains_key(&(repo_id, key.to_string()))
+    }
+
+    /// Returns `None` when a newer build was registered for the key while
+    /// this one was still waiting in the queue.
+    pub fn subscribe(
+        &self,
+        repo_id: u64,
+        key: &str,
+        git_ref: &str,
+        build_id: Uuid,
+    ) -> Option<CancelToken> {
+        let mut builds = self.builds.lock().unwrap();
+
+        match builds.get(&(repo_id, key.to_string())) {
+            Some(current) if current.build_id == build_id => {
+                Some(CancelToken(current.cancel.subscribe()))
+            }
+            Some(_) => None,
+            // Jobs normally get registered when enqueued; track it now if not.
+            None => {
+                let (cancel, receiver) = watch::channel(false);
+                let build = InFlight {
+                    build_id,
+                    git_ref: git_ref.to_string(),
+                    cancel,
+                };
+                builds.insert((repo_id, key.to_string()), build);
+                Some(CancelToken(receiver))
+            }
+        }
+    }
+
+    pub fn finish(&self, repo_id: u64, key: &str, build_id: Uuid) {
+        let mut builds = self.builds.lock().unwrap();
+        let key = (repo_id, key.to_string());
+
+        if builds.get(&key).is_some_and(|current| current.build_id == build_id) {
+            builds.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn cancelling_a_branch_stops_its_pushes_and_pull_requests() {
+        let registry = BuildRegistry::default();
+        let (push, pull, other) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        registry.supersede(1, "refs/heads/feature", "refs/heads/feature", push);
+        registry.supersede(1, "pull/7", "refs/heads/feature", pull);
+        registry.supersede(1, "refs/heads/main", "refs/heads/main", other);
+
+        let push_token = registry.subscribe(1, "refs/heads/feature", "refs/heads/feature", push).unwrap();
+        let stopping = registry.cancel_branch(1, "refs/heads/feature");
+        assert!(push_token.is_cancelled());
+
+        // Queued when the branch was deleted: it starts already cancelled.
+        let pull_token = registry.subscribe(1, "pull/7", "refs/heads/feature", pull).unwrap();
+        assert!(pull_token.is_cancelled());
+        let main_token = registry.subscribe(1, "refs/heads/main", "refs/heads/main", other).unwrap();
+        assert!(!main_token.is_cancelled());
+
+        registry.finish(1, "refs/heads/feature", push);
+        registry.finish(1, "pull/7", pull);
+        stopping.finished().await;
+
+        // Nothing is left behind for the deleted branch.
+        assert_eq!(registry.builds.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn cancelling_a_key_leaves_the_branch_builds_alone() {
+        let registry = BuildRegistry::default();
+        let (push, pull) = (Uuid::new_v4(), Uuid::new_v4());
+        registry.supersede(1, "refs/heads/feature", "refs/heads/feature", push);
+        registry.supersede(1, "pull/7", "refs/heads/feature", pull);
+
+        let pull_token = registry.subscribe(1, "pull/7", "refs/heads/feature", pull).unwrap();
+        let stopping = registry.cancel_key(1, "pull/7");
+        assert!(pull_token.is_cancelled());
+        let push_token = registry.subscribe(1, "refs/heads/feature", "refs/heads/feature", push).unwrap();
+        assert!(!push_token.is_cancelled());
+
+        registry.finish(1, "pull/7", pull);
+        stopping.finished().await;
+    }
+}
@@ -0,0 +1,210 @@
+use crate::github::checks::CommitChecks;
+use crate::github::commits::compare_changed_files;
+use crate::github::models::PushCommit;
+use crate::nur::config::NurFunction;
+use crate::nur::queue::BuildJob;
+use crate::supabase::crud::get_deployed_commits;
+use postgrest::Postgrest;
+use std::collections::HashMap;
+
+/// Push payloads list at most this many commits; a push with more needs the
+/// compare API to know everything it touched.
+const MAX_PAYLOAD_COMMITS: usize = 20;
+
+/// Changing the nurfile can affect any function.
+const NURFILE: &str = "nurfile.yaml";
+
+/// Every path touched by the pushed commits, or `None` when the payload
+/// may not list all of them.
+pub fn paths_from_commits(commits: &[PushCommit]) -> Option<Vec<String>> {
+    if commits.is_empty() || commits.len() >= MAX_PAYLOAD_COMMITS {
+        return None;
+    }
+
+    let mut paths: Vec<String> = commits
+        .iter()
+        .flat_map(|c| c.added.iter().chain(&c.removed).chain(&c.modified))
+        .cloned()
+        .collect();
+    paths.sort();
+    paths.dedup();
+    Some(paths)
+}
+
+/// The commit each function's production artifact was built from, by
+/// function name. Every deploying branch writes the same artifact key, so
+/// this is the latest successful deployment from any of them, not only from
+/// the branch being built.
+pub async fn last_deployed_commits(
+    client: &Postgrest,
+    project_id: &str,
+) -> Result<HashMap<String, String>, String> {
+    let rows = get_deployed_commits(client, project_id).await?;
+
+    let mut commits = HashMap::new();
+    for row in rows {
+        let (Some(name), Some(sha)) = (
+            row["functions"]["name"].as_str(),
+            row["project_builds"]["commit_sha"].as_str(),
+        ) else {
+            continue;
+        };
+        // Rows come newest first, so the first one per function wins.
+        commits.entry(name.to_string()).or_insert_with(|| sha.to_string());
+    }
+    Ok(commits)
+}
+
+/// Splits `functions` into those changed since their last deployment and
+/// those that weren't. Diffing against what is deployed, rather
+/// than against the push's `before`, keeps the changes of a push that was
+/// superseded or failed: they are still undeployed, so they still count.
+/// Functions never deployed, or whose changes can't be listed, are always
+/// built.
+pub async fn split_by_changes(
+    job: &BuildJob,
+    checks: &CommitChecks,
+    deployed: &HashMap<String, String>,
+    functions: Vec<NurFunction>,
+) -> (Vec<NurFunction>, Vec<NurFunction>) {
+    let mut diffs: HashMap<&str, Option<Vec<String>>> = HashMap::new();
+    let mut affected = Vec::new();
+    let mut unchanged = Vec::new();
+
+    for func in functions {
+        let changed = match deployed.get(&func.name) {
+            Some(base) => {
+                if !diffs.contains_key(base.as_str()) {
+                    diffs.insert(base, changed_since(job, checks, base).await);
+                }
+                diffs[base.as_str()].as_deref()
+            }
+            None => None,
+        };
+
+        match changed {
+            Some(changed) if !function_affected(&func, changed) => unchanged.push(func),
+            _ => affected.push(func),
+        }
+    }
+    (affected, unchanged)
+}
+
+/// Paths changed between `base` and the commit being built. `None` means
+/// "unknown": GitHub truncated or failed the comparison.
+async fn changed_since(job: &BuildJob, checks: &CommitChecks, base: &str) -> Option<Vec<String>> {
+    if base == job.head_sha {
+        return Some(Vec::new());
+    }
+    if let Some(paths) = payload_paths(job, base) {
+        return Some(paths.to_vec());
+    }
+
+    let compared = async {
+        let token = checks.token().await?;
+        compare_changed_files(&checks.github, &token, &checks.owner, &checks.repo, base, &job.head_sha)
+            .await
+    };
+    match compared.await {
+        Ok(paths) => paths,
+        Err(e) => {
+            println!("⚠️ Could not compare {}...{}, building everything: {}", base, job.head_sha, e);
+            None
+        }
+    }
+}
+
+/// The push payload lists what changed since `before`, so it only answers
+/// for a function deployed from exactly that commit.
+fn payload_paths<'a>(job: &'a BuildJob, base: &str) -> Option<&'a [String]> {
+    if job.before.as_deref() != Some(base) {
+        return None;
+    }
+    job.changed_paths.as_deref()
+}
+
+/// Whether any changed path is inside the function's directory or one of its
+/// `watch:` paths.
+pub fn function_affected(func: &NurFunction, changed: &[String]) -> bool {
+    if changed.iter().any(|path| path == NURFILE) {
+        return true;
+    }
+
+    std::iter::once(&func.directory)
+        .chain(&func.watch)
+        .any(|watched| changed.iter().any(|path| path_within(path, watched)))
+}
+
+fn path_within(path: &str, watched: &str) -> bool {
+    let watched = watched.trim_start_matches("./").trim_matches('/');
+    if watched.is_empty() || watched == "." {
+        return true;
+    }
+
+    path == watched
+        || path
+            .strip_prefix(watched)
+            .is_some_and(|rest| rest.starts_with('/'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn function(directory: &str, watch: &[&str]) -> NurFunction {
+        NurFunction {
+            watch: watch.iter().map(|w| w.to_string()).collect(),
+            ..NurFunction::for_tests(directory)
+        }
+    }
+
+    fn changed(paths: &[&str]) -> Vec<String> {
+        paths.iter().map(|p| p.to_string()).collect()
+    }
+
+    #[test]
+    fn changes_inside_the_directory_affect_the_function() {
+        let func = function("./functions/hello/", &[]);
+
+        assert!(function_affected(&func, &changed(&["functions/hello/src/lib.rs"])));
+        assert!(!function_affected(&func, &changed(&["functions/hello-world/src/lib.rs"])));
+        assert!(!function_affected(&func, &changed(&["README.md"])));
+    }
+
+    #[test]
+    fn watch_paths_and_the_nurfile_affect_the_function() {
+        let func = function("functions/hello", &["shared", "Cargo.lock"]);
+
+        assert!(function_affected(&func, &changed(&["shared/src/lib.rs"])));
+        assert!(function_affected(&func, &changed(&["Cargo.lock"])));
+        assert!(function_affected(&func, &changed(&["nurfile.yaml"])));
+        assert!(!function_affected(&func, &changed(&["shared-old/lib.rs"])));
+    }
+
+    #[test]
+    fn payload_paths_only_answer_for_the_previous_commit() {
+        let job = BuildJob {
+            id: uuid::Uuid::nil(),
+            repo_id: 1,
+            owner: "nur-org".to_string(),
+            repo_name: "hello".to_string(),
+            clone_url: "https://github.com/nur-org/hello.git".to_string(),
+            head_sha: "c3".to_string(),
+            git_ref: "refs/heads/main".to_string(),
+            default_branch: "main".to_string(),
+            installation_id: 1,
+            kind: Default::default(),
+            before: Some("c2".to_string()),
+            changed_paths: Some(changed(&["functions/hello/src/lib.rs"])),
+        };
+
+        assert_eq!(payload_paths(&job, "c2"), Some(&changed(&["functions/hello/src/lib.rs"])[..]));
+        // Deployed from an older commit: c1..c2 isn't in the payload.
+        assert_eq!(payload_paths(&job, "c1"), None);
+    }
+
+    #[test]
+    fn a_function_at_the_root_is_always_affected() {
+        assert!(function_affected(&function("/", &[]), &changed(&["anything.txt"])));
+    }
+}
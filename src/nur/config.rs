@@ -3,6 +3,93 @@ use serde::Deserialize;
 #[derive(Debug, Deserialize)]
 pub struct NurFile {
     pub functions: Vec<NurFunction>,
+    pub branches: Option<NurBranches>,
+    /// Deploy pull requests under `previews/<pr>/` instead of only building them.
+    #[serde(default)]
+    pub previews: bool,
+    #[serde(default)]
+    pub source: NurSource,
+}
+
+/// Opt-in extras fetched along with the commit. Both need the `git` source
+/// fetcher.
+#[derive(Debug, Deserialize, Clone, Copy, Default)]
+pub struct NurSource {
+    /// Initialize submodules, recursively and shallowly.
+    #[serde(default)]
+    pub submodules: bool,
+    /// Replace Git LFS pointer files with their content.
+    #[serde(default)]
+    pub lfs: bool,
+}
+
+impl NurSource {
+    pub fn is_empty(&self) -> bool {
+        !self.submodules && !self.lfs
+    }
+}
+
+/// Which branches get built and which of those get deployed. A branch that
+/// matches neither list is skipped. Patterns may use `*` as a wildcard,
+/// e.g. `release/*`.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct NurBranches {
+    #[serde(default)]
+    pub deploy: Vec<String>,
+    #[serde(default)]
+    pub build_only: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BranchRule {
+    Deploy,
+    BuildOnly,
+    Skip,
+}
+
+impl NurFile {
+    /// Without a `branches:` section only the repository's default branch is
+    /// built, and it is deployed.
+    pub fn branch_rule(&self, branch: &str, default_branch: &str) -> BranchRule {
+        let Some(branches) = &self.branches else {
+            return if branch == default_branch {
+                BranchRule::Deploy
+            } else {
+                BranchRule::Skip
+            };
+        };
+
+        if branches.deploy.iter().any(|p| branch_matches(p, branch)) {
+            BranchRule::Deploy
+        } else if branches.build_only.iter().any(|p| branch_matches(p, branch)) {
+            BranchRule::BuildOnly
+        } else {
+            BranchRule::Skip
+        }
+    }
+}
+
+fn branch_matches(pattern: &str, branch: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or("");
+    let Some(mut rest) = branch.strip_prefix(first) else {
+        return false;
+    };
+
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        // No wildcard at all, so it has to be an exact match.
+        return rest.is_empty();
+    };
+
+    for part in middle {
+        match rest.find(part) {
+            Some(idx) => rest = &rest[idx + part.len()..],
+            None => return false,
+        }
+    }
+
+    rest.ends_with(last)
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -11,6 +98,10 @@ pub struct NurFunction {
     pub directory: String,
     pub template: String,
     pub build: NurBuild,
+    /// Paths outside `directory` that also trigger a rebuild, e.g. a shared
+    /// crate.
+    #[serde(default)]
+    pub watch: Vec<String>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -18,3 +109,82 @@ pub struct NurBuild {
     pub command: String,
     pub output: String,
 }
+
+#[cfg(test)]
+impl NurFunction {
+    /// A Rust function named `hello` built in `directory`.
+    pub fn for_tests(directory: &str) -> NurFunction {
+        NurFunction {
+            name: "hello".to_string(),
+            directory: directory.to_string(),
+            template: "rust".to_string(),
+            build: NurBuild {
+                command: "cargo build".to_string(),
+                output: "hello.wasm".to_string(),
+            },
+            watch: Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn patterns_without_wildcards_match_exactly() {
+        assert!(branch_matches("main", "main"));
+        assert!(!branch_matches("main", "main2"));
+        assert!(!branch_matches("main", "my-main"));
+        assert!(!branch_matches("", "main"));
+    }
+
+    #[test]
+    fn wildcards_match_any_run_of_characters() {
+        assert!(branch_matches("release/*", "release/1.0"));
+        assert!(branch_matches("release/*", "release/"));
+        assert!(!branch_matches("release/*", "release"));
+        assert!(branch_matches("*", "anything/at/all"));
+        assert!(branch_matches("*-hotfix", "v2-hotfix"));
+        assert!(!branch_matches("*-hotfix", "v2-hotfix-old"));
+        assert!(branch_matches("feature/*/wip", "feature/login/wip"));
+        assert!(!branch_matches("feature/*/wip", "feature/login/done"));
+    }
+
+    #[test]
+    fn wildcard_parts_do_not_overlap() {
+        assert!(!branch_matches("a*a", "a"));
+        assert!(branch_matches("a*a", "aa"));
+        assert!(!branch_matches("*ab*b", "ab"));
+        assert!(branch_matches("*ab*b", "abb"));
+    }
+
+    #[test]
+    fn default_branch_is_the_only_one_built_without_rules() {
+        let nurfile = NurFile {
+            functions: Vec::new(),
+            branches: None,
+            previews: false,
+            source: NurSource::default(),
+        };
+
+        assert_eq!(nurfile.branch_rule("main", "main"), BranchRule::Deploy);
+        assert_eq!(nurfile.branch_rule("dev", "main"), BranchRule::Skip);
+    }
+
+    #[test]
+    fn deploy_rules_win_over_build_only() {
+        let nurfile = NurFile {
+            functions: Vec::new(),
+            branches: Some(NurBranches {
+                deploy: vec!["main".to_string()],
+                build_only: vec!["*".to_string()],
+            }),
+            previews: false,
+            source: NurSource::default(),
+        };
+
+        assert_eq!(nurfile.branch_rule("main", "main"), BranchRule::Deploy);
+        assert_eq!(nurfile.branch_rule("feature/x", "main"), BranchRule::BuildOnly);
+    }
+}
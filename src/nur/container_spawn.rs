@@ -1,38 +1,306 @@
+use crate::nur::build_cache::{cached_artifact, store_artifact};
+use crate::nur::cancel::CancelToken;
 use crate::nur::compress::compress_to_zstd;
 use crate::nur::config::NurFunction;
-use crate::nur::upload_s3::upload_to_s3;
-use crate::supabase::crud::{get_function_id, insert_function_deployed};
+use crate::nur::diagnostics::parse_diagnostics;
+use crate::nur::preview::{preview_environment, preview_key};
+use crate::nur::report::BuildReport;
+use crate::nur::upload_s3::{copy_in_s3, upload_to_s3};
+use crate::supabase::crud::{get_function_id, insert_function_deployed, upsert_preview_deployment};
+use crate::utils::redact_tokens;
 use postgrest::Postgrest;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
 use tokio::process::Command;
+use tokio::task::JoinHandle;
 use tokio::time::{timeout, Duration};
 use tracing::warn;
+use uuid::Uuid;
 
+/// Where a successful build ends up.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeployTarget {
+    /// Build-only branches and plain PR builds: compile and compress, but
+    /// leave the current deployment alone.
+    BuildOnly,
+    Production,
+    Preview { pr: u64 },
+}
+
+impl DeployTarget {
+    pub fn artifact_key(&self, function_id: &str) -> Option<String> {
+        match self {
+            DeployTarget::BuildOnly => None,
+            DeployTarget::Production => Some(format!("builds/{}.wasm.zst", function_id)),
+            DeployTarget::Preview { pr } => Some(preview_key(*pr, function_id)),
+        }
+    }
+
+    /// The GitHub environment deploys to this target are recorded under.
+    pub fn environment(&self) -> Option<String> {
+        match self {
+            DeployTarget::BuildOnly => None,
+            DeployTarget::Production => Some("production".to_string()),
+            DeployTarget::Preview { pr } => Some(preview_environment(*pr)),
+        }
+    }
+}
+
+/// What a successful function build produced.
+#[derive(Debug, Clone)]
+pub struct FunctionArtifact {
+    pub name: String,
+    /// Where it was uploaded, `None` for build-only targets.
+    pub key: Option<String>,
+    pub wasm_size: u64,
+    pub compressed_size: u64,
+}
+
+/// State shared by every function of a single project build.
+#[derive(Clone)]
+pub struct FunctionBuildContext {
+    pub tmp_dir: String,
+    pub builds_dir: PathBuf,
+    pub client: Postgrest,
+    pub s3_bucket: String,
+    pub project_id: String,
+    pub build_id: String,
+    pub branch_name: String,
+    pub target: DeployTarget,
+    pub job_id: Uuid,
+    pub cancel: CancelToken,
+    pub report: BuildReport,
+    /// Content hash of the function's build inputs, `None` when it can't
+    /// be cached.
+    pub input_hash: Option<String>,
+    /// Whether a fresh build may populate the cache. Only pushes to the
+    /// repository itself do, so a pull request can't plant an artifact
+    /// that a later deploy would pick up.
+    pub store_in_cache: bool,
+}
+
+/// Builder image for a nurfile template.
+pub fn builder_image(template: &str) -> Option<&'static str> {
+    match template.to_lowercase().as_str() {
+        "rust" => Some("ghcr.io/fisirc/rust-builder:latest"),
+        "node" => Some("nur/node-builder"),
+        "go" => Some("nur/go-builder"),
+        _ => None,
+    }
+}
+
+/// Stable container name so a superseded build can be killed by name.
+fn container_name(job_id: &Uuid, function_name: &str) -> String {
+    let function_name: String = function_name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '-' })
+        .collect();
+    format!("nur-{}-{}", job_id, function_name)
+}
+
+/// Echoes a container output stream to our logs, prefixed with the
+/// function name, feeds it to the live check run log and keeps the lines
+/// for diagnostics parsing.
+fn collect_output<R>(
+    stream: Option<R>,
+    function_name: &str,
+    report: &BuildReport,
+) -> JoinHandle<Vec<String>>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    let function_name = function_name.to_string();
+    let report = report.clone();
+
+    tokio::spawn(async move {
+        let mut collected = Vec::new();
+        let Some(stream) = stream else {
+            return collected;
+        };
+
+        let mut lines = BufReader::new(stream).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            let line = redact_tokens(&line);
+            println!("{}: {}", function_name, line);
+            report.log_line(&function_name, &line);
+            collected.push(line);
+        }
+        collected
+    })
+}
+
+/// A compressed artifact ready to deploy.
+struct CompiledFunction {
+    zip_path: PathBuf,
+    wasm_size: u64,
+    compressed_size: u64,
+}
+
+/// Where the deployed artifact comes from.
+enum ArtifactSource {
+    /// Built just now, uploaded from this file.
+    Built(PathBuf),
+    /// Reused from the build cache, copied from this key.
+    Cached(String),
+}
+
+/// Builds a single function and, unless the target is `BuildOnly`, uploads it.
+/// When the build cache has an artifact for the same inputs, podman is
+/// skipped and that artifact is deployed instead.
 pub async fn build_and_deploy_function(
     func: &NurFunction,
-    tmp_dir: String,
-    builds_dir: std::path::PathBuf,
-    client: Postgrest,
-    s3_bucket: String,
-    project_id: String,
-    build_id: String,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let image = match func.template.to_lowercase().as_str() {
-        "rust" => "ghcr.io/fisirc/rust-builder:latest",
-        "node" => "nur/node-builder",
-        "go" => "nur/go-builder",
-        _ => return Err(format!("Unsupported template: {}", func.template).into()),
-    };
+    ctx: FunctionBuildContext,
+) -> Result<FunctionArtifact, Box<dyn std::error::Error + Send + Sync>> {
+    let FunctionBuildContext {
+        tmp_dir,
+        builds_dir,
+        client,
+        s3_bucket,
+        project_id,
+        build_id,
+        branch_name,
+        target,
+        job_id,
+        mut cancel,
+        report,
+        input_hash,
+        store_in_cache,
+    } = ctx;
+
+    let image = builder_image(&func.template)
+        .ok_or_else(|| format!("Unsupported template: {}", func.template))?;
     println!("{f}: ⚠️ We chose the image'{}'", image, f=func.name);
 
+    let cached = match &input_hash {
+        Some(hash) => cached_artifact(&s3_bucket, hash).await,
+        None => None,
+    };
+
+    let (mut artifact, source) = match cached {
+        Some(cached) => {
+            println!("{f}: ♻️ Inputs unchanged, reusing {}", cached.key, f=func.name);
+            report.progress(&func.name, "cache hit");
+            let artifact = FunctionArtifact {
+                name: func.name.clone(),
+                key: None,
+                wasm_size: cached.wasm_size,
+                compressed_size: cached.compressed_size,
+            };
+            (artifact, ArtifactSource::Cached(cached.key))
+        }
+        None => {
+            let compiled =
+                compile_function(func, image, &tmp_dir, &builds_dir, &job_id, &mut cancel, &report)
+                    .await?;
+            if let (Some(hash), true) = (&input_hash, store_in_cache) {
+                store_artifact(&s3_bucket, hash, &compiled.zip_path, compiled.wasm_size).await;
+            }
+            let artifact = FunctionArtifact {
+                name: func.name.clone(),
+                key: None,
+                wasm_size: compiled.wasm_size,
+                compressed_size: compiled.compressed_size,
+            };
+            (artifact, ArtifactSource::Built(compiled.zip_path))
+        }
+    };
+
+    if target == DeployTarget::BuildOnly {
+        println!("{f}: 🧪 Build-only, skipping deployment", f=func.name);
+        report.progress(&func.name, "built, not deployed");
+        return Ok(artifact);
+    }
+
+    // Last chance to bail out before a superseded build overwrites the
+    // artifact of the newer one.
+    if cancel.is_cancelled() {
+        return Err(format!("Build for '{}' was cancelled", func.name).into());
+    }
+
+    let function_id = match get_function_id(&client, &project_id, &func.name).await {
+        Ok(id) => id,
+        Err(e) => {
+            return Err(format!("Function ID error: {:?}", e).into());
+        }
+    };
+
+    let s3_key = target
+        .artifact_key(&function_id)
+        .ok_or("No artifact key for this deploy target")?;
+    report.progress(&func.name, "uploading");
+    match &source {
+        ArtifactSource::Built(zip_path) => upload_to_s3(&s3_bucket, &s3_key, zip_path).await,
+        ArtifactSource::Cached(cache_key) => copy_in_s3(&s3_bucket, cache_key, &s3_key).await,
+    }
+    .map_err(|e| format!("Upload to S3 failed: {:?}", e))?;
+
+    if let DeployTarget::Preview { pr } = target {
+        timeout(
+            Duration::from_secs(10),
+            upsert_preview_deployment(
+                &client,
+                &project_id,
+                &function_id,
+                &build_id,
+                pr,
+                &branch_name,
+                &s3_key,
+            ),
+        )
+        .await?
+        .map_err(|e| format!("Insert preview_deployment failed: {}", e))?;
+
+        println!("{f}: 👀 Deployed preview for PR #{}", pr, f=func.name);
+        report.progress(&func.name, "deployed as preview");
+        artifact.key = Some(s3_key);
+        return Ok(artifact);
+    }
+
+    timeout(
+        Duration::from_secs(10),
+        insert_function_deployed(
+            &client,
+            &function_id,
+            &build_id,
+            "success",
+            artifact.wasm_size,
+            artifact.compressed_size,
+            input_hash.as_deref(),
+        ),
+    )
+    .await?
+    .map_err(|e| format!("Insert function_deployed failed: {}", e))?;
+
+    println!("{f}: 📦 Marked function '{}' as deployed", func.name, f=func.name);
+    report.progress(&func.name, "deployed");
+    artifact.key = Some(s3_key);
+    Ok(artifact)
+}
+
+/// Runs the function's build command in its builder image and compresses
+/// the output.
+async fn compile_function(
+    func: &NurFunction,
+    image: &str,
+    tmp_dir: &str,
+    builds_dir: &Path,
+    job_id: &Uuid,
+    cancel: &mut CancelToken,
+    report: &BuildReport,
+) -> Result<CompiledFunction, Box<dyn std::error::Error + Send + Sync>> {
     let work_dir = format!("/app/{}", func.directory.trim_start_matches('/'));
-    let host_dir = tmp_dir.clone();
+    let host_dir = tmp_dir.to_string();
+
+    let name = container_name(job_id, &func.name);
 
     // Build using podman (privileged mode)
-    let status = Command::new("podman")
+    let mut child = Command::new("podman")
         .args([
             "run",
             "--rm",
+            "--name",
+            &name,
             "-v",
             &format!("{host_dir}:/app"),
             "-w",
@@ -42,8 +310,34 @@ pub async fn build_and_deploy_function(
             "-c",
             &func.build.command,
         ])
-        .status()
-        .await?;
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    report.progress(&func.name, "building");
+    let stdout = collect_output(child.stdout.take(), &func.name, report);
+    let stderr = collect_output(child.stderr.take(), &func.name, report);
+
+    let status = tokio::select! {
+        status = child.wait() => status?,
+        _ = cancel.cancelled() => {
+            println!("{f}: 🛑 Build superseded, killing container {}", name, f=func.name);
+            if let Err(e) = Command::new("podman").args(["rm", "-f", &name]).status().await {
+                warn!("Could not kill container {}: {}", name, e);
+            }
+            let _ = child.wait().await;
+            return Err(format!("Build for '{}' was cancelled", func.name).into());
+        }
+    };
+
+    let mut output = stdout.await.unwrap_or_default();
+    output.extend(stderr.await.unwrap_or_default());
+
+    let diagnostics = parse_diagnostics(&func.template, &func.directory, &output);
+    if !diagnostics.is_empty() {
+        println!("{f}: 🔎 Found {} diagnostic(s)", diagnostics.len(), f=func.name);
+        report.add_diagnostics(&func.name, diagnostics);
+    }
 
     if !status.success() {
         return Err(format!("Build failed for '{}'", func.name).into());
@@ -51,7 +345,7 @@ pub async fn build_and_deploy_function(
 
     println!("{f}: ✅ Build OK", f=func.name);
 
-    let output_path = Path::new(&tmp_dir)
+    let output_path = Path::new(tmp_dir)
         .join(func.directory.trim_start_matches('/'))
         .join(func.build.output.trim_start_matches('/'));
 
@@ -64,33 +358,19 @@ pub async fn build_and_deploy_function(
         return Err(format!("Failed to copy .wasm: {:?}", e).into());
     }
 
+    report.progress(&func.name, "compressing");
     let zip_path = builds_dir.join(format!("{}.wasm.zst", func.name));
     if let Err(e) = compress_to_zstd(&wasm_dest, &zip_path) {
         return Err(format!("Compression failed: {:?}", e).into());
     }
 
-    let function_id = match get_function_id(&client, &project_id, &func.name).await {
-        Ok(id) => id,
-        Err(e) => {
-            return Err(format!("Function ID error: {:?}", e).into());
-        }
+    let compiled = CompiledFunction {
+        wasm_size: tokio::fs::metadata(&wasm_dest).await?.len(),
+        compressed_size: tokio::fs::metadata(&zip_path).await?.len(),
+        zip_path,
     };
-
-    let s3_key = format!("builds/{}.wasm.zst", function_id);
-    if let Err(e) = upload_to_s3(&s3_bucket, &s3_key, &zip_path).await {
-        return Err(format!("Upload to S3 failed: {:?}", e).into());
-    }
     if let Err(e) = tokio::fs::remove_file(&wasm_dest).await {
         warn!("Could not remove intermediate file: {}", e);
     }
-
-    timeout(
-        Duration::from_secs(10),
-        insert_function_deployed(&client, &function_id, &build_id, "success"),
-    )
-    .await?
-    .map_err(|e| format!("Insert function_deployed failed: {}", e))?;
-
-    println!("{f}: 📦 Marked function '{}' as deployed", func.name, f=func.name);
-    Ok(())
+    Ok(compiled)
 }
@@ -0,0 +1,304 @@
+use crate::github::checks::Annotation;
+use serde_json::Value;
+
+/// Extracts diagnostics from a function's build output. `function_dir` is
+/// where the build ran, relative to the repository root, so reported paths
+/// can be turned into repository paths.
+pub fn parse_diagnostics(template: &str, function_dir: &str, lines: &[String]) -> Vec<Annotation> {
+    match template.to_lowercase().as_str() {
+        "rust" => parse_cargo_output(function_dir, lines),
+        "go" => lines
+            .iter()
+            .filter_map(|line| parse_go_error(function_dir, line))
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Understands `cargo --message-format=json` lines, and falls back to the
+/// human-readable `error: ...` / `--> file:line:col` pairs for builds that
+/// don't ask for JSON.
+fn parse_cargo_output(function_dir: &str, lines: &[String]) -> Vec<Annotation> {
+    let mut diagnostics = Vec::new();
+    let mut pending: Option<(&'static str, String)> = None;
+
+    for line in lines {
+        if line.starts_with('{') {
+            if let Some(diagnostic) = parse_cargo_json(function_dir, line) {
+                diagnostics.push(diagnostic);
+            }
+            continue;
+        }
+
+        let trimmed = line.trim_start();
+        if let Some((level, message)) = human_header(trimmed) {
+            pending = Some((level, message));
+        } else if let Some(location) = trimmed.strip_prefix("--> ") {
+            if let (Some((level, message)), Some((file, line_no, column))) =
+                (pending.take(), split_location(location))
+            {
+                if let Some(path) = repo_path(function_dir, file) {
+                    diagnostics.push(Annotation {
+                        path,
+                        start_line: line_no,
+                        end_line: line_no,
+                        start_column: column,
+                        end_column: column,
+                        annotation_level: level,
+                        title: "cargo".to_string(),
+                        message,
+                    });
+                }
+            }
+        }
+    }
+
+    diagnostics
+}
+
+fn human_header(line: &str) -> Option<(&'static str, String)> {
+    let (level, rest) = if let Some(rest) = line.strip_prefix("error") {
+        ("failure", rest)
+    } else if let Some(rest) = line.strip_prefix("warning") {
+        ("warning", rest)
+    } else {
+        return None;
+    };
+
+    // Skip the optional `[E0308]` code, then expect `: message`.
+    let rest = match rest.strip_prefix('[') {
+        Some(code) => &code[code.find(']')? + 1..],
+        None => rest,
+    };
+    let message = rest.strip_prefix(": ")?;
+
+    // Summary lines such as "error: could not compile `foo`" have no location
+    // and are followed by nothing useful, but they are harmless here.
+    Some((level, message.to_string()))
+}
+
+fn parse_cargo_json(function_dir: &str, line: &str) -> Option<Annotation> {
+    let json: Value = serde_json::from_str(line).ok()?;
+    if json["reason"] != "compiler-message" {
+        return None;
+    }
+
+    let message = &json["message"];
+    let annotation_level = match message["level"].as_str()? {
+        "error" | "error: internal compiler error" => "failure",
+        "warning" => "warning",
+        _ => return None,
+    };
+
+    let span = message["spans"]
+        .as_array()?
+        .iter()
+        .find(|span| span["is_primary"] == true)?;
+    let path = repo_path(function_dir, span["file_name"].as_str()?)?;
+    let start_line = span["line_start"].as_u64()?;
+    let end_line = span["line_end"].as_u64()?;
+
+    // GitHub only accepts columns on single-line annotations.
+    let (start_column, end_column) = if start_line == end_line {
+        (span["column_start"].as_u64(), span["column_end"].as_u64())
+    } else {
+        (None, None)
+    };
+
+    Some(Annotation {
+        path,
+        start_line,
+        end_line,
+        start_column,
+        end_column,
+        annotation_level,
+        title: message["code"]["code"]
+            .as_str()
+            .map(|code| format!("cargo {}", code))
+            .unwrap_or_else(|| "cargo".to_string()),
+        message: message["rendered"]
+            .as_str()
+            .or(message["message"].as_str())?
+            .to_string(),
+    })
+}
+
+/// `./main.go:12:5: undefined: foo`
+fn parse_go_error(function_dir: &str, line: &str) -> Option<Annotation> {
+    let (location, message) = line.split_once(": ")?;
+    let (file, line_no, column) = split_location(location)?;
+    if !file.ends_with(".go") {
+        return None;
+    }
+
+    Some(Annotation {
+        path: repo_path(function_dir, file)?,
+        start_line: line_no,
+        end_line: line_no,
+        start_column: column,
+        end_column: column,
+        annotation_level: "failure",
+        title: "go build".to_string(),
+        message: message.to_string(),
+    })
+}
+
+/// Splits `file:line[:col]`.
+fn split_location(location: &str) -> Option<(&str, u64, Option<u64>)> {
+    let mut parts = location.trim().rsplitn(3, ':');
+    let last = parts.next()?;
+    let middle = parts.next()?;
+
+    match parts.next() {
+        Some(file) => Some((file, middle.parse().ok()?, last.parse().ok())),
+        None => Some((middle, last.parse().ok()?, None)),
+    }
+}
+
+/// Turns a path reported from inside the container into a repository path.
+/// Paths outside the repository (registry sources, the toolchain) yield
+/// `None` since GitHub can't annotate them.
+fn repo_path(function_dir: &str, file: &str) -> Option<String> {
+    let path = if let Some(in_repo) = file.strip_prefix("/app/") {
+        in_repo.to_string()
+    } else if file.starts_with('/') {
+        return None;
+    } else {
+        format!("{}/{}", function_dir.trim_matches('/'), file)
+    };
+    normalize(&path)
+}
+
+/// Resolves `.` and `..`, so `functions/hello/../shared/lib.rs` becomes
+/// `shared/lib.rs`. `None` when the path climbs out of the repository.
+fn normalize(path: &str) -> Option<String> {
+    let mut parts = Vec::new();
+    for part in path.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop()?;
+            }
+            part => parts.push(part),
+        }
+    }
+    (!parts.is_empty()).then(|| parts.join("/"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(output: &str) -> Vec<String> {
+        output.lines().map(|line| line.to_string()).collect()
+    }
+
+    const CARGO_JSON_ERROR: &str = r#"{"reason":"compiler-message","package_id":"hello 0.1.0 (path+file:///app/functions/hello)","manifest_path":"/app/functions/hello/Cargo.toml","message":{"rendered":"error[E0425]: cannot find value `x` in this scope\n --> src/lib.rs:3:5\n","$message_type":"diagnostic","children":[],"code":{"code":"E0425","explanation":null},"level":"error","message":"cannot find value `x` in this scope","spans":[{"byte_end":30,"byte_start":29,"column_end":6,"column_start":5,"expansion":null,"file_name":"src/lib.rs","is_primary":true,"label":"not found in this scope","line_end":3,"line_start":3,"suggested_replacement":null,"suggestion_applicability":null,"text":[]}]}}"#;
+
+    const CARGO_JSON_MULTILINE_WARNING: &str = r#"{"reason":"compiler-message","message":{"rendered":"warning: unused function\n","code":null,"level":"warning","message":"function `helper` is never used","spans":[{"column_end":9,"column_start":5,"file_name":"../shared/src/lib.rs","is_primary":false,"line_end":1,"line_start":1},{"column_end":2,"column_start":1,"file_name":"../shared/src/lib.rs","is_primary":true,"line_end":12,"line_start":10}]}}"#;
+
+    const CARGO_JSON_REGISTRY: &str = r#"{"reason":"compiler-message","message":{"rendered":"warning: deprecated\n","code":null,"level":"warning","message":"deprecated","spans":[{"column_end":2,"column_start":1,"file_name":"/usr/local/cargo/registry/src/index.crates.io-6f17d22bba15001f/serde-1.0.0/src/lib.rs","is_primary":true,"line_end":1,"line_start":1}]}}"#;
+
+    #[test]
+    fn cargo_json_errors_point_at_the_primary_span() {
+        let output = [
+            r#"{"reason":"compiler-artifact","package_id":"serde 1.0.0"}"#,
+            CARGO_JSON_ERROR,
+            r#"{"reason":"build-finished","success":false}"#,
+        ]
+        .map(str::to_string);
+
+        let diagnostics = parse_diagnostics("rust", "functions/hello", &output);
+        assert_eq!(diagnostics.len(), 1);
+        let error = &diagnostics[0];
+        assert_eq!(error.path, "functions/hello/src/lib.rs");
+        assert_eq!((error.start_line, error.end_line), (3, 3));
+        assert_eq!((error.start_column, error.end_column), (Some(5), Some(6)));
+        assert_eq!(error.annotation_level, "failure");
+        assert_eq!(error.title, "cargo E0425");
+        assert!(error.message.starts_with("error[E0425]"));
+    }
+
+    #[test]
+    fn cargo_json_outside_the_function_dir() {
+        let diagnostics = parse_diagnostics(
+            "rust",
+            "functions/hello",
+            &[CARGO_JSON_MULTILINE_WARNING.to_string(), CARGO_JSON_REGISTRY.to_string()],
+        );
+
+        // The registry path can't be annotated, the sibling crate can.
+        assert_eq!(diagnostics.len(), 1);
+        let warning = &diagnostics[0];
+        assert_eq!(warning.path, "functions/shared/src/lib.rs");
+        assert_eq!((warning.start_line, warning.end_line), (10, 12));
+        assert_eq!((warning.start_column, warning.end_column), (None, None));
+        assert_eq!(warning.annotation_level, "warning");
+        assert_eq!(warning.title, "cargo");
+    }
+
+    #[test]
+    fn rustc_human_readable_output() {
+        let output = lines(
+            "   Compiling hello v0.1.0 (/app/functions/hello)\n\
+             error[E0308]: mismatched types\n \
+             --> src/lib.rs:7:12\n  \
+             |\n\
+             warning: unused variable: `y`\n \
+             --> /app/shared/src/lib.rs:2:9\n\
+             error: could not compile `hello` (lib) due to 1 previous error",
+        );
+
+        let diagnostics = parse_diagnostics("rust", "/functions/hello/", &output);
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!(diagnostics[0].path, "functions/hello/src/lib.rs");
+        assert_eq!(diagnostics[0].start_line, 7);
+        assert_eq!(diagnostics[0].start_column, Some(12));
+        assert_eq!(diagnostics[0].annotation_level, "failure");
+        assert_eq!(diagnostics[0].message, "mismatched types");
+        assert_eq!(diagnostics[1].path, "shared/src/lib.rs");
+        assert_eq!(diagnostics[1].annotation_level, "warning");
+        assert_eq!(diagnostics[1].message, "unused variable: `y`");
+    }
+
+    #[test]
+    fn go_build_errors() {
+        let output = lines(
+            "# example.com/hello\n\
+             ./main.go:12:5: undefined: foo\n\
+             ../shared/util.go:3:1: syntax error: non-declaration statement outside function body\n\
+             /usr/local/go/src/fmt/print.go:1:1: not ours\n\
+             note: module requires Go 1.22",
+        );
+
+        let diagnostics = parse_diagnostics("go", "functions/hello", &output);
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!(diagnostics[0].path, "functions/hello/main.go");
+        assert_eq!((diagnostics[0].start_line, diagnostics[0].start_column), (12, Some(5)));
+        assert_eq!(diagnostics[0].message, "undefined: foo");
+        assert_eq!(diagnostics[1].path, "functions/shared/util.go");
+        assert_eq!(diagnostics[1].start_line, 3);
+    }
+
+    #[test]
+    fn other_templates_have_no_parser() {
+        assert!(parse_diagnostics("node", "fn", &lines("./index.js:1:1: error")).is_empty());
+    }
+
+    #[test]
+    fn split_location_with_and_without_columns() {
+        assert_eq!(split_location("src/lib.rs:3:5"), Some(("src/lib.rs", 3, Some(5))));
+        assert_eq!(split_location(" main.go:12 "), Some(("main.go", 12, None)));
+        assert_eq!(split_location("C:/a.rs:1:2"), Some(("C:/a.rs", 1, Some(2))));
+        assert_eq!(split_location("src/lib.rs"), None);
+        assert_eq!(split_location("src/lib.rs:x:5"), None);
+    }
+
+    #[test]
+    fn paths_leaving_the_repository_are_dropped() {
+        assert_eq!(repo_path("functions/hello", "./src/lib.rs").as_deref(), Some("functions/hello/src/lib.rs"));
+        assert_eq!(repo_path("/", "main.go").as_deref(), Some("main.go"));
+        assert_eq!(repo_path("functions/hello", "../../../etc/passwd"), None);
+        assert_eq!(repo_path("functions/hello", "/root/.cargo/registry/lib.rs"), None);
+    }
+}
@@ -1,5 +1,14 @@
 pub mod build;
+pub mod build_cache;
+pub mod cancel;
+pub mod changes;
 pub mod compress;
 pub mod config;
 pub mod container_spawn;
+pub mod diagnostics;
+pub mod preview;
+pub mod queue;
+pub mod report;
+pub mod size_report;
+pub mod source;
 pub mod upload_s3;
@@ -0,0 +1,142 @@
+use crate::github::client::{GitHubClient, GitHubError};
+use crate::github::deployments::{create_deployment_status, latest_deployment_id};
+use crate::github::tokens::InstallationAuth;
+use crate::nur::container_spawn::FunctionArtifact;
+use crate::nur::upload_s3::delete_from_s3;
+use crate::supabase::crud::{
+    delete_branch_preview_deployments, delete_preview_deployments,
+    get_branch_preview_deployments, get_preview_deployments, get_project_id, get_supabase_client,
+};
+use std::collections::BTreeSet;
+
+/// Hidden marker that identifies our preview comment among the PR comments.
+pub const PREVIEW_COMMENT_MARKER: &str = "<!-- nur-preview -->";
+
+/// The repository whose previews are cleaned up, and how to reach it on
+/// GitHub.
+pub struct PreviewRepo {
+    pub id: u64,
+    pub owner: String,
+    pub name: String,
+    pub github: GitHubClient,
+    pub auth: InstallationAuth,
+}
+
+pub fn preview_key(pr: u64, function_id: &str) -> String {
+    format!("previews/{}/{}.wasm.zst", pr, function_id)
+}
+
+/// The GitHub environment a pull request's previews are deployed to.
+pub fn preview_environment(pr: u64) -> String {
+    format!("preview/pr-{}", pr)
+}
+
+/// Public URL of a preview artifact when `PREVIEW_BASE_URL` is set, the raw
+/// S3 location otherwise.
+pub fn preview_location(s3_bucket: &str, key: &str) -> String {
+    match std::env::var("PREVIEW_BASE_URL") {
+        Ok(base) => format!("{}/{}", base.trim_end_matches('/'), key),
+        Err(_) => format!("s3://{}/{}", s3_bucket, key),
+    }
+}
+
+pub fn preview_comment(pr: u64, head_sha: &str, artifacts: &[FunctionArtifact]) -> String {
+    let s3_bucket = std::env::var("S3_BUCKET").unwrap_or_default();
+
+    let mut body = format!(
+        "{}\n### 👀 Nur preview for #{}\n\nDeployed from `{}`:\n\n| Function | Location |\n|---|---|\n",
+        PREVIEW_COMMENT_MARKER, pr, head_sha
+    );
+    for artifact in artifacts {
+        if let Some(key) = &artifact.key {
+            body.push_str(&format!(
+                "| `{}` | {} |\n",
+                artifact.name,
+                preview_location(&s3_bucket, key)
+            ));
+        }
+    }
+    body.push_str("\nPreviews are removed when the pull request is closed.");
+    body
+}
+
+/// Deletes every preview artifact and row recorded for the pull request,
+/// and marks its environment inactive.
+pub async fn cleanup_preview(repo: &PreviewRepo, pr: u64) -> Result<(), String> {
+    let client = get_supabase_client()?;
+    let project_id = get_project_id(&client, &repo.id.to_string()).await?;
+
+    let rows = get_preview_deployments(&client, &project_id, pr).await?;
+    if rows.is_empty() {
+        println!("🧹 No previews to clean up for PR #{}", pr);
+    } else {
+        delete_preview_objects(&rows).await?;
+        delete_preview_deployments(&client, &project_id, pr).await?;
+        println!("🧹 Removed {} preview artifact(s) for PR #{}", rows.len(), pr);
+    }
+
+    // A preview build that failed still left a deployment behind.
+    deactivate_preview_environment(repo, pr).await;
+    Ok(())
+}
+
+/// Deletes the previews deployed from a branch that no longer exists, and
+/// marks the environments of their pull requests inactive.
+pub async fn cleanup_branch_previews(repo: &PreviewRepo, branch: &str) -> Result<(), String> {
+    let client = get_supabase_client()?;
+    let project_id = get_project_id(&client, &repo.id.to_string()).await?;
+
+    let rows = get_branch_preview_deployments(&client, &project_id, branch).await?;
+    if rows.is_empty() {
+        println!("🧹 No previews to clean up for branch '{}'", branch);
+        return Ok(());
+    }
+
+    delete_preview_objects(&rows).await?;
+    delete_branch_preview_deployments(&client, &project_id, branch).await?;
+    println!("🧹 Removed {} preview artifact(s) for branch '{}'", rows.len(), branch);
+
+    let prs: BTreeSet<u64> = rows.iter().filter_map(|row| row["pr_number"].as_u64()).collect();
+    for pr in prs {
+        deactivate_preview_environment(repo, pr).await;
+    }
+    Ok(())
+}
+
+/// Posts `inactive` on the environment's latest deployment, so GitHub stops
+/// listing the removed preview as live. Failing to do so is only logged:
+/// the preview itself is gone either way.
+async fn deactivate_preview_environment(repo: &PreviewRepo, pr: u64) {
+    let environment = preview_environment(pr);
+
+    let result = async {
+        let token = repo.auth.token().await?;
+        let github = &repo.github;
+        let Some(id) = latest_deployment_id(github, &token, &repo.owner, &repo.name, &environment)
+            .await?
+        else {
+            return Ok::<_, GitHubError>(());
+        };
+        create_deployment_status(github, &token, &repo.owner, &repo.name, id, "inactive", "Preview removed")
+            .await
+    }
+    .await;
+
+    match result {
+        Ok(()) => println!("🧹 Marked {} inactive", environment),
+        Err(e) => println!("⚠️ Failed to mark {} inactive: {}", environment, e),
+    }
+}
+
+async fn delete_preview_objects(rows: &[serde_json::Value]) -> Result<(), String> {
+    let s3_bucket = std::env::var("S3_BUCKET").map_err(|e| e.to_string())?;
+
+    for row in rows {
+        if let Some(key) = row["s3_key"].as_str() {
+            delete_from_s3(&s3_bucket, key)
+                .await
+                .map_err(|e| format!("Failed to delete {}: {}", key, e))?;
+        }
+    }
+    Ok(())
+}
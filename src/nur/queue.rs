@@ -0,0 +1,503 @@
+use crate::app_state::AppState;
+use crate::github::checks::{
+    check_run_is_open, complete_check_run, CheckRunOutput, CommitChecks, REBUILD_ACTION,
+    ROLLBACK_ACTION,
+};
+use crate::github::comments::upsert_issue_comment;
+use crate::github::deployments::{create_deployment_status, latest_deployment_state};
+use crate::github::tokens::{get_installation_token, InstallationAuth};
+use crate::nur::build::{run_nur_build, BuildOutcome};
+use crate::nur::cancel::CancelToken;
+use crate::nur::container_spawn::DeployTarget;
+use crate::nur::preview::{preview_comment, PREVIEW_COMMENT_MARKER};
+use crate::nur::report::BuildReport;
+use crate::nur::size_report::post_size_comment;
+use crate::supabase::crud::{
+    get_build_job, get_supabase_client, get_unfinished_build_jobs, insert_build_job,
+    update_build_job,
+};
+use crate::utils::redact_tokens;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot, Mutex};
+use tokio::task::JoinHandle;
+use uuid::Uuid;
+
+/// How often the in-progress check run gets the latest build logs.
+const LOG_UPDATE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Deployment states a build still had to move on from when it stopped.
+const UNFINISHED_DEPLOYMENT_STATES: [&str; 3] = ["queued", "pending", "in_progress"];
+
+/// Everything a worker needs to build a push without going back to the
+/// original webhook payload. It is also what gets persisted in the
+/// `build_jobs` table so the job can be picked up again after a restart.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BuildJob {
+    pub id: Uuid,
+    pub repo_id: u64,
+    pub owner: String,
+    pub repo_name: String,
+    pub clone_url: String,
+    pub head_sha: String,
+    pub git_ref: String,
+    #[serde(default)]
+    pub default_branch: String,
+    pub installation_id: u64,
+    #[serde(default)]
+    pub kind: BuildKind,
+    /// The commit the branch pointed to before the push. `None` marks
+    /// rebuilds and rollbacks, which build every function.
+    #[serde(default)]
+    pub before: Option<String>,
+    /// Paths changed between `before` and `head_sha` when the push payload
+    /// listed all of them, saving a compare call.
+    #[serde(default)]
+    pub changed_paths: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub enum BuildKind {
+    #[default]
+    Push,
+    /// Built to report a check run on the PR head, never deployed.
+    PullRequest { number: u64, base_ref: String },
+}
+
+impl BuildJob {
+    /// Builds sharing this key supersede each other: pushes per branch,
+    /// pull request builds per pull request.
+    pub fn supersede_key(&self) -> String {
+        match &self.kind {
+            BuildKind::Push => self.git_ref.clone(),
+            BuildKind::PullRequest { number, .. } => format!("pull/{}", number),
+        }
+    }
+}
+
+pub type BuildQueue = mpsc::UnboundedSender<BuildJob>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+impl JobStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobStatus::Queued => "queued",
+            JobStatus::Running => "running",
+            JobStatus::Succeeded => "succeeded",
+            JobStatus::Failed => "failed",
+            JobStatus::Cancelled => "cancelled",
+        }
+    }
+}
+
+/// Persists the job as `queued` and hands it to the workers. The row is
+/// written first so a crash between both steps still leaves a trace that
+/// `recover_orphaned_jobs` can pick up.
+pub async fn enqueue_build(state: &AppState, mut job: BuildJob) -> Result<(), String> {
+    if state.builds_in_flight.is_in_flight(job.repo_id, &job.supersede_key()) {
+        // The build being superseded won't deploy its own changes, so this
+        // push's changes alone don't cover what is undeployed.
+        job.changed_paths = None;
+    }
+
+    let client = get_supabase_client()?;
+    let payload = serde_json::to_value(&job).map_err(|e| e.to_string())?;
+    insert_build_job(&client, &job.id.to_string(), &payload).await?;
+
+    state
+        .builds_in_flight
+        .supersede(job.repo_id, &job.supersede_key(), &job.git_ref, job.id);
+    state.build_queue.send(job).map_err(|e| e.to_string())
+}
+
+async fn set_job_status(job_id: &Uuid, status: JobStatus, mut fields: Value) {
+    fields["status"] = json!(status.as_str());
+
+    let result = match get_supabase_client() {
+        Ok(client) => update_build_job(&client, &job_id.to_string(), &fields).await,
+        Err(e) => Err(e),
+    };
+
+    if let Err(e) = result {
+        println!("⚠️ Failed to mark build {} as {}: {}", job_id, status.as_str(), e);
+    }
+}
+
+/// Spawns `workers` tasks that pull jobs from the queue and run the Nur
+/// pipeline for each of them, one job per worker at a time. Each job runs in
+/// a task of its own, so a panic in the pipeline fails that job instead of
+/// taking the worker down with it.
+pub fn spawn_build_workers(
+    state: Arc<AppState>,
+    receiver: mpsc::UnboundedReceiver<BuildJob>,
+    workers: usize,
+) {
+    let receiver = Arc::new(Mutex::new(receiver));
+
+    for worker_id in 0..workers.max(1) {
+        let state = state.clone();
+        let receiver = receiver.clone();
+
+        tokio::spawn(async move {
+            loop {
+                let job = match receiver.lock().await.recv().await {
+                    Some(job) => job,
+                    None => break,
+                };
+
+                println!("👷 Worker {worker_id} picked build {}", job.id);
+                let handle = tokio::spawn(process_build_job(state.clone(), job.clone()));
+                if let Err(e) = handle.await {
+                    println!("💥 Build {} crashed: {}", job.id, e);
+                    fail_crashed_job(&state, &job).await;
+                    state
+                        .builds_in_flight
+                        .finish(job.repo_id, &job.supersede_key(), job.id);
+                }
+            }
+        });
+    }
+}
+
+async fn process_build_job(state: Arc<AppState>, job: BuildJob) {
+    let key = job.supersede_key();
+
+    let Some(cancel) = state
+        .builds_in_flight
+        .subscribe(job.repo_id, &key, &job.git_ref, job.id)
+    else {
+        println!("⏭️ Build {} was superseded before it started", job.id);
+        set_job_status(&job.id, JobStatus::Cancelled, json!({})).await;
+        return;
+    };
+    if cancel.is_cancelled() {
+        println!("⏭️ Build {} was cancelled before it started", job.id);
+        set_job_status(&job.id, JobStatus::Cancelled, json!({})).await;
+        state.builds_in_flight.finish(job.repo_id, &key, job.id);
+        return;
+    }
+
+    run_build_job(&state, &job, cancel).await;
+    state.builds_in_flight.finish(job.repo_id, &key, job.id);
+}
+
+async fn run_build_job(state: &Arc<AppState>, job: &BuildJob, cancel: CancelToken) {
+    set_job_status(&job.id, JobStatus::Running, json!({})).await;
+
+    // Builds can outlive an installation token, so the checks fetch one
+    // from the cache for every call instead of holding on to it.
+    let checks = CommitChecks {
+        github: state.github.clone(),
+        auth: InstallationAuth::new(state.clone(), job.installation_id),
+        owner: job.owner.clone(),
+        repo: job.repo_name.clone(),
+        head_sha: job.head_sha.clone(),
+    };
+
+    let check_run_id = match checks.create("Nur functions compilation").await {
+        Ok(check_run_id) => {
+            println!("✅ Check run created with ID: {}", check_run_id);
+            check_run_id
+        }
+        Err(e) => {
+            println!("❌ Failed to create check run: {}", e);
+            set_job_status(&job.id, JobStatus::Failed, json!({ "error": e.to_string() })).await;
+            return;
+        }
+    };
+
+    set_job_status(
+        &job.id,
+        JobStatus::Running,
+        json!({ "check_run_id": check_run_id }),
+    )
+    .await;
+
+    let status: JobStatus;
+    let conclusion: &str;
+    let mut summary: String;
+    // Skipped builds get no buttons: rebuilding would skip again.
+    let mut actions = vec![REBUILD_ACTION];
+
+    let report = BuildReport::default();
+    let streamer = stream_logs_to_check_run(checks.clone(), check_run_id, report.clone());
+
+    // Box<dyn Error> is not Send, so flatten it before awaiting anything else.
+    let result = run_nur_build(job, cancel.clone(), report.clone(), checks.clone())
+        .await
+        .map_err(|e| redact_tokens(&e.to_string()));
+    // Wait for a PATCH in flight, so it can't land on top of the final output.
+    streamer.stop().await;
+
+    // A build that finished despite a newer push still deployed everything,
+    // so only a failure can be blamed on the cancellation.
+    match result {
+        Err(_) if cancel.is_cancelled() => {
+            status = JobStatus::Cancelled;
+            conclusion = "cancelled";
+            summary = "Superseded by a newer push, this build was cancelled.".to_string();
+            println!("🛑 Build {} cancelled", job.id);
+        }
+        Ok(BuildOutcome::Built {
+            functions,
+            skipped,
+            target,
+            artifacts,
+        }) => {
+            status = JobStatus::Succeeded;
+            conclusion = "success";
+            if functions.is_empty() {
+                summary = "No function changed, nothing was built.\n".to_string();
+            } else {
+                summary = "Functions compiled successfully! Summary:\n".to_string();
+            }
+            for func in functions {
+                summary.push_str(&format!("- Function: {}, Dir: {}\n", func.name, func.directory));
+            }
+            if !skipped.is_empty() {
+                summary.push_str("\nSkipped, unchanged by this push (current deployment kept):\n");
+                for func in skipped {
+                    summary.push_str(&format!("- Function: {}, Dir: {}\n", func.name, func.directory));
+                }
+            }
+            match (&target, &job.kind) {
+                (DeployTarget::BuildOnly, BuildKind::PullRequest { .. }) => {
+                    summary.push_str("\nPull request builds are not deployed unless `previews: true` is set.\n");
+                }
+                (DeployTarget::BuildOnly, BuildKind::Push) => {
+                    summary.push_str("\nThis branch is build-only, nothing was deployed.\n");
+                }
+                (DeployTarget::Preview { pr }, _) => {
+                    summary.push_str(&format!("\nDeployed as a preview for #{}.\n", pr));
+                    let body = preview_comment(*pr, &job.head_sha, &artifacts);
+                    let posted = async {
+                        let token = checks.token().await?;
+                        upsert_issue_comment(
+                            &state.github,
+                            &token,
+                            &job.owner,
+                            &job.repo_name,
+                            *pr,
+                            PREVIEW_COMMENT_MARKER,
+                            &body,
+                        )
+                        .await
+                    };
+                    if let Err(e) = posted.await {
+                        println!("❌ Failed to post preview comment on #{}: {}", pr, e);
+                    }
+                }
+                (DeployTarget::Production, _) => actions.push(ROLLBACK_ACTION),
+            }
+            if let BuildKind::PullRequest { number, base_ref } = &job.kind {
+                if let Err(e) =
+                    post_size_comment(&checks, job.repo_id, *number, base_ref, &artifacts).await
+                {
+                    println!("❌ Failed to post size comment on #{}: {}", number, e);
+                }
+            }
+            println!("✅ Build {} completed successfully.", job.id);
+        }
+        Ok(BuildOutcome::Skipped(reason)) => {
+            status = JobStatus::Succeeded;
+            conclusion = "neutral";
+            summary = format!("Build skipped: {}", reason);
+            actions.clear();
+            println!("⏭️ Build {} skipped: {}", job.id, reason);
+        }
+        Err(e) => {
+            status = JobStatus::Failed;
+            conclusion = "failure";
+            summary = format!("Build failed: {}", e);
+            println!("❌ Build {} error: {}", job.id, e);
+        }
+    }
+
+    let output = CheckRunOutput {
+        summary: summary.clone(),
+        text: report.render_text(),
+        annotations: report.diagnostics(),
+        actions,
+    };
+    match checks.complete(check_run_id, conclusion, &output).await {
+        Ok(_) => {
+            println!("🔔 Check completion notified: conclusion={conclusion}");
+        }
+        Err(e) => {
+            println!("🔔❌ Failed to notify check completion for check_run_id={check_run_id}: {e:?}");
+        }
+    };
+
+    set_job_status(&job.id, status, json!({ "summary": summary })).await;
+}
+
+/// The task spawned by `stream_logs_to_check_run`.
+struct LogStreamer {
+    stop: oneshot::Sender<()>,
+    handle: JoinHandle<()>,
+}
+
+impl LogStreamer {
+    /// Returns once the last update has been sent.
+    async fn stop(self) {
+        let _ = self.stop.send(());
+        let _ = self.handle.await;
+    }
+}
+
+/// Periodically replaces the check run's `output.text` with the progress
+/// and log tail of every function, until stopped.
+fn stream_logs_to_check_run(
+    checks: CommitChecks,
+    check_run_id: u64,
+    report: BuildReport,
+) -> LogStreamer {
+    let (stop, mut stopped) = oneshot::channel();
+
+    let handle = tokio::spawn(async move {
+        let mut interval = tokio::time::interval(LOG_UPDATE_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = &mut stopped => return,
+            }
+            if !report.take_dirty() {
+                continue;
+            }
+
+            let output = CheckRunOutput {
+                summary: "Build in progress...".to_string(),
+                text: report.render_text(),
+                ..Default::default()
+            };
+            if let Err(e) = checks.update_output(check_run_id, &output).await {
+                println!("⚠️ Failed to stream logs to check run {}: {}", check_run_id, e);
+            }
+        }
+    });
+
+    LogStreamer { stop, handle }
+}
+
+/// Called once on startup. Jobs that never left the queue are handed to the
+/// workers again; jobs that were mid-build cannot be resumed safely (their
+/// workspace and containers are gone), so they are failed and their check
+/// runs and deployment closed instead of staying `in_progress` forever.
+pub async fn recover_orphaned_jobs(state: &AppState) -> Result<(), String> {
+    let client = get_supabase_client()?;
+    let jobs = get_unfinished_build_jobs(&client).await?;
+
+    for row in jobs {
+        let job: BuildJob = match serde_json::from_value(row["payload"].clone()) {
+            Ok(job) => job,
+            Err(e) => {
+                println!("⚠️ Skipping unreadable build job {}: {}", row["id"], e);
+                continue;
+            }
+        };
+
+        if row["status"].as_str() == Some(JobStatus::Queued.as_str()) {
+            println!("♻️ Re-queueing build {}", job.id);
+            state
+                .builds_in_flight
+                .supersede(job.repo_id, &job.supersede_key(), &job.git_ref, job.id);
+            state.build_queue.send(job).map_err(|e| e.to_string())?;
+            continue;
+        }
+
+        let summary = "The builder restarted while this build was running. Push again to rebuild.";
+        println!("🧹 Failing orphaned build {}", job.id);
+        fail_abandoned_job(state, &job, &row, summary).await;
+    }
+
+    Ok(())
+}
+
+/// A job whose task panicked never got to close its check run; do it from
+/// what was persisted so far.
+async fn fail_crashed_job(state: &AppState, job: &BuildJob) {
+    let row = match get_supabase_client() {
+        Ok(client) => get_build_job(&client, &job.id.to_string()).await,
+        Err(e) => Err(e),
+    };
+    let row = row.unwrap_or_else(|e| {
+        println!("⚠️ Could not read build job {}: {}", job.id, e);
+        Value::Null
+    });
+
+    let summary = "The builder crashed while running this build. Push again or click Rebuild.";
+    fail_abandoned_job(state, job, &row, summary).await;
+}
+
+/// Fails a job that stopped without reporting, closing what it left open
+/// on GitHub according to its `build_jobs` row: the build's check run, the
+/// per-function ones still in progress and an unfinished deployment.
+async fn fail_abandoned_job(state: &AppState, job: &BuildJob, row: &Value, summary: &str) {
+    match get_installation_token(state, job.installation_id).await {
+        Ok(token) => close_abandoned_job(state, &token, job, row, summary).await,
+        Err(e) => println!("❌ Failed to get installation token for build {}: {}", job.id, e),
+    }
+
+    set_job_status(&job.id, JobStatus::Failed, json!({ "error": summary })).await;
+}
+
+async fn close_abandoned_job(
+    state: &AppState,
+    token: &str,
+    job: &BuildJob,
+    row: &Value,
+    summary: &str,
+) {
+    let (github, owner, repo) = (&state.github, &job.owner, &job.repo_name);
+    let output = CheckRunOutput {
+        summary: summary.to_string(),
+        ..Default::default()
+    };
+
+    let mut check_run_ids: Vec<u64> = Vec::new();
+    if let Some(functions) = row["function_check_run_ids"].as_object() {
+        check_run_ids.extend(functions.values().filter_map(Value::as_u64));
+    }
+    // Functions that finished keep their conclusion.
+    let mut open_runs = Vec::new();
+    for check_run_id in check_run_ids {
+        match check_run_is_open(github, token, owner, repo, check_run_id).await {
+            Ok(true) => open_runs.push(check_run_id),
+            Ok(false) => {}
+            Err(e) => println!("⚠️ Could not read check run {check_run_id}: {e}"),
+        }
+    }
+    open_runs.extend(row["check_run_id"].as_u64());
+
+    for check_run_id in open_runs {
+        if let Err(e) =
+            complete_check_run(github, token, owner, repo, check_run_id, "failure", &output).await
+        {
+            println!("🔔❌ Failed to close check run {check_run_id}: {e:?}");
+        }
+    }
+
+    if let Some(deployment_id) = row["deployment_id"].as_u64() {
+        let result = match latest_deployment_state(github, token, owner, repo, deployment_id).await {
+            Ok(Some(state)) if !UNFINISHED_DEPLOYMENT_STATES.contains(&state.as_str()) => Ok(()),
+            Ok(_) => {
+                create_deployment_status(github, token, owner, repo, deployment_id, "error", summary)
+                    .await
+            }
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            println!("⚠️ Failed to close deployment {}: {}", deployment_id, e);
+        }
+    }
+}
@@ -0,0 +1,160 @@
+use crate::github::checks::Annotation;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// GitHub rejects check run `output.text` longer than 65535 characters;
+/// leave some room for the markdown around the logs.
+const MAX_TEXT_CHARS: usize = 60_000;
+/// Lines kept in memory per function, more than ever fits in the text.
+const MAX_TAIL_LINES: usize = 500;
+
+#[derive(Default)]
+struct FunctionLog {
+    progress: Vec<String>,
+    tail: VecDeque<String>,
+}
+
+/// Collects what the function builds of one job want to tell the check run.
+/// It is created by the worker and outlives `run_nur_build`, so whatever
+/// was gathered is still available when the build fails.
+#[derive(Clone, Default)]
+pub struct BuildReport {
+    diagnostics: Arc<Mutex<BTreeMap<String, Vec<Annotation>>>>,
+    logs: Arc<Mutex<BTreeMap<String, FunctionLog>>>,
+    dirty: Arc<AtomicBool>,
+}
+
+impl BuildReport {
+    pub fn add_diagnostics(&self, function: &str, diagnostics: Vec<Annotation>) {
+        self.diagnostics
+            .lock()
+            .unwrap()
+            .entry(function.to_string())
+            .or_default()
+            .extend(diagnostics);
+    }
+
+    pub fn diagnostics(&self) -> Vec<Annotation> {
+        self.diagnostics.lock().unwrap().values().flatten().cloned().collect()
+    }
+
+    pub fn function_diagnostics(&self, function: &str) -> Vec<Annotation> {
+        self.diagnostics
+            .lock()
+            .unwrap()
+            .get(function)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Records a pipeline stage (cloned, building, compressing...) for a function.
+    pub fn progress(&self, function: &str, stage: &str) {
+        let line = format!("{} {}", chrono::Utc::now().format("%H:%M:%S"), stage);
+        self.logs
+            .lock()
+            .unwrap()
+            .entry(function.to_string())
+            .or_default()
+            .progress
+            .push(line);
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    pub fn log_line(&self, function: &str, line: &str) {
+        let mut logs = self.logs.lock().unwrap();
+        let tail = &mut logs.entry(function.to_string()).or_default().tail;
+        if tail.len() == MAX_TAIL_LINES {
+            tail.pop_front();
+        }
+        tail.push_back(line.to_string());
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether anything changed since the last call.
+    pub fn take_dirty(&self) -> bool {
+        self.dirty.swap(false, Ordering::Relaxed)
+    }
+
+    /// Markdown for the check run `output.text`: the progress of every
+    /// function followed by as much of its log tail as fits.
+    pub fn render_text(&self) -> String {
+        let logs = self.logs.lock().unwrap();
+        if logs.is_empty() {
+            return String::new();
+        }
+
+        let budget = MAX_TEXT_CHARS / logs.len();
+        let mut text = String::new();
+
+        for (function, log) in logs.iter() {
+            text.push_str(&format!("### {}\n\n", function));
+            text.push_str(&render_log(log, budget));
+            text.push('\n');
+        }
+
+        text
+    }
+
+    /// Same as `render_text`, for a single function's own check run.
+    pub fn render_function_text(&self, function: &str) -> String {
+        self.logs
+            .lock()
+            .unwrap()
+            .get(function)
+            .map(|log| render_log(log, MAX_TEXT_CHARS))
+            .unwrap_or_default()
+    }
+}
+
+fn render_log(log: &FunctionLog, budget: usize) -> String {
+    let mut section = String::new();
+    for stage in &log.progress {
+        section.push_str(&format!("- {}\n", stage));
+    }
+
+    // 16 chars for the code fence around the tail.
+    let tail = tail_within(&log.tail, budget.saturating_sub(section.len() + 16));
+    if !tail.is_empty() {
+        section.push_str(&format!("\n```text\n{}\n```\n", tail));
+    }
+
+    section
+}
+
+/// The last lines of `tail` that fit in `max_chars`.
+fn tail_within(tail: &VecDeque<String>, max_chars: usize) -> String {
+    let mut used = 0;
+    let mut lines = Vec::new();
+
+    for line in tail.iter().rev() {
+        // Backticks could close the code fence early.
+        let line = line.replace("```", "'''");
+        if used + line.len() + 1 > max_chars {
+            break;
+        }
+        used += line.len() + 1;
+        lines.push(line);
+    }
+
+    lines.reverse();
+    lines.join("\n")
+}
+
+/// `1536` -> `1.5 KiB`.
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
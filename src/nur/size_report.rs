@@ -0,0 +1,129 @@
+use crate::github::checks::CommitChecks;
+use crate::github::comments::upsert_issue_comment;
+use crate::nur::container_spawn::FunctionArtifact;
+use crate::nur::report::format_bytes;
+use crate::supabase::crud::{get_branch_deployment_sizes, get_project_id, get_supabase_client};
+use std::collections::HashMap;
+
+/// Hidden marker that identifies our size comment among the PR comments.
+const SIZE_COMMENT_MARKER: &str = "<!-- nur-size-report -->";
+
+#[derive(Debug, Clone, Copy)]
+pub struct FunctionSizes {
+    pub wasm_size: u64,
+    pub compressed_size: u64,
+}
+
+/// Sizes of the latest successful deployment of every function on `branch`.
+pub async fn branch_sizes(
+    repo_id: u64,
+    branch: &str,
+) -> Result<HashMap<String, FunctionSizes>, String> {
+    let client = get_supabase_client()?;
+    let project_id = get_project_id(&client, &repo_id.to_string()).await?;
+    let rows = get_branch_deployment_sizes(&client, &project_id, branch).await?;
+
+    let mut sizes = HashMap::new();
+    for row in rows {
+        let (Some(name), Some(wasm_size), Some(compressed_size)) = (
+            row["functions"]["name"].as_str(),
+            row["wasm_size"].as_u64(),
+            row["compressed_size"].as_u64(),
+        ) else {
+            continue;
+        };
+
+        // Rows come newest first, so the first one per function wins.
+        sizes.entry(name.to_string()).or_insert(FunctionSizes {
+            wasm_size,
+            compressed_size,
+        });
+    }
+    Ok(sizes)
+}
+
+/// Posts the size table on the pull request, or updates the one posted by
+/// an earlier build.
+pub async fn post_size_comment(
+    checks: &CommitChecks,
+    repo_id: u64,
+    pr: u64,
+    base_ref: &str,
+    artifacts: &[FunctionArtifact],
+) -> Result<(), String> {
+    let baseline = match branch_sizes(repo_id, base_ref).await {
+        Ok(baseline) => baseline,
+        Err(e) => {
+            println!("⚠️ No size baseline for '{}': {}", base_ref, e);
+            HashMap::new()
+        }
+    };
+    let body = size_comment(&checks.head_sha, base_ref, artifacts, &baseline);
+
+    upsert_issue_comment(
+        &checks.github,
+        &checks.token().await.map_err(|e| e.to_string())?,
+        &checks.owner,
+        &checks.repo,
+        pr,
+        SIZE_COMMENT_MARKER,
+        &body,
+    )
+    .await
+    .map(|_| ())
+    .map_err(|e| e.to_string())
+}
+
+pub fn size_comment(
+    head_sha: &str,
+    base_ref: &str,
+    artifacts: &[FunctionArtifact],
+    baseline: &HashMap<String, FunctionSizes>,
+) -> String {
+    let mut body = format!(
+        "{}\n### 📏 Nur function sizes\n\nBuilt from `{}`, compared with the last deployment of `{}`:\n\n\
+         | Function | `.wasm` | Δ | Compressed | Δ |\n|---|---:|---:|---:|---:|\n",
+        SIZE_COMMENT_MARKER, head_sha, base_ref
+    );
+
+    let mut artifacts: Vec<&FunctionArtifact> = artifacts.iter().collect();
+    artifacts.sort_by(|a, b| a.name.cmp(&b.name));
+
+    for artifact in artifacts {
+        let previous = baseline.get(&artifact.name);
+        body.push_str(&format!(
+            "| `{}` | {} | {} | {} | {} |\n",
+            artifact.name,
+            format_bytes(artifact.wasm_size),
+            format_delta(artifact.wasm_size, previous.map(|p| p.wasm_size)),
+            format_bytes(artifact.compressed_size),
+            format_delta(artifact.compressed_size, previous.map(|p| p.compressed_size)),
+        ));
+    }
+
+    body.push_str("\nThis comment is updated on every push to the pull request.");
+    body
+}
+
+fn format_delta(current: u64, previous: Option<u64>) -> String {
+    let Some(previous) = previous else {
+        return "new".to_string();
+    };
+    if current == previous {
+        return "±0".to_string();
+    }
+
+    let (sign, diff) = if current > previous {
+        ('+', current - previous)
+    } else {
+        ('-', previous - current)
+    };
+    // Growth is what hurts cold starts, so make it stand out.
+    let flag = if sign == '+' { "🔺 " } else { "" };
+    if previous == 0 {
+        return format!("{}{}{}", flag, sign, format_bytes(diff));
+    }
+
+    let percent = diff as f64 * 100.0 / previous as f64;
+    format!("{}{}{} ({}{:.1}%)", flag, sign, format_bytes(diff), sign, percent)
+}
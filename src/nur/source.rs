@@ -0,0 +1,563 @@
+use crate::github::client::GitHubClient;
+use crate::nur::config::NurSource;
+use crate::utils::redact_tokens;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use futures::future::BoxFuture;
+use serde::Deserialize;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use tokio::process::Command;
+
+/// Largest tarball we are willing to download.
+const DEFAULT_TARBALL_MAX_BYTES: u64 = 200 * 1024 * 1024;
+/// Largest tree we are willing to extract from it, against zip bombs.
+const DEFAULT_TARBALL_MAX_EXTRACTED_BYTES: u64 = 1024 * 1024 * 1024;
+const MAX_TARBALL_ENTRIES: usize = 100_000;
+
+/// Which commit to fetch and where to put it.
+pub struct SourceRequest<'a> {
+    pub owner: &'a str,
+    pub repo: &'a str,
+    pub clone_url: &'a str,
+    pub head_sha: &'a str,
+    pub token: &'a str,
+    pub dest: &'a Path,
+}
+
+/// The commit that ended up in the workspace.
+pub struct FetchedCommit {
+    pub sha: String,
+    /// First line of the commit message.
+    pub message: String,
+}
+
+/// Puts the source of a commit into an empty workspace directory.
+pub trait SourceFetcher: Send + Sync {
+    fn fetch<'a>(&'a self, request: &'a SourceRequest<'a>)
+        -> BoxFuture<'a, Result<FetchedCommit, String>>;
+
+    /// Adds what the nurfile's `source:` section asked for on top of an
+    /// already fetched workspace.
+    fn fetch_extras<'a>(
+        &'a self,
+        request: &'a SourceRequest<'a>,
+        source: NurSource,
+    ) -> BoxFuture<'a, Result<(), String>>;
+}
+
+/// Picks how a project's source is fetched from its `source_fetch` setting:
+/// `tarball`, or `git` (the default).
+pub fn source_fetcher(method: Option<&str>, github: &GitHubClient) -> Box<dyn SourceFetcher> {
+    match method {
+        Some("tarball") => Box::new(TarballFetcher {
+            github: github.clone(),
+            max_bytes: env_limit("TARBALL_MAX_BYTES", DEFAULT_TARBALL_MAX_BYTES),
+            max_extracted_bytes: env_limit(
+                "TARBALL_MAX_EXTRACTED_BYTES",
+                DEFAULT_TARBALL_MAX_EXTRACTED_BYTES,
+            ),
+        }),
+        Some("git") | None => Box::new(GitFetcher),
+        Some(other) => {
+            println!("⚠️ Unknown source_fetch '{}', using git", other);
+            Box::new(GitFetcher)
+        }
+    }
+}
+
+fn env_limit(name: &str, default: u64) -> u64 {
+    std::env::var(name)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
+
+/// Shallow-fetches the single commit with git.
+pub struct GitFetcher;
+
+impl SourceFetcher for GitFetcher {
+    fn fetch<'a>(
+        &'a self,
+        request: &'a SourceRequest<'a>,
+    ) -> BoxFuture<'a, Result<FetchedCommit, String>> {
+        Box::pin(async move {
+            let dir = request.dest.to_str().ok_or("Workspace path is not UTF-8")?;
+
+            // Fetch only the pushed commit instead of cloning whatever HEAD
+            // the default branch points to by the time we get here. The
+            // checkout is bind-mounted into the build containers, so the
+            // remote must not carry credentials.
+            let clone_url = strip_credentials(request.clone_url);
+            let auth = GitAuth::new(&clone_url, request.token);
+            run_git(dir, &["init", "--quiet"], None).await?;
+            run_git(dir, &["remote", "add", "origin", &clone_url], None).await?;
+            run_git(dir, &["fetch", "--depth=1", "origin", request.head_sha], auth.as_ref()).await?;
+            run_git(dir, &["checkout", "--quiet", "--detach", "FETCH_HEAD"], None).await?;
+
+            let log_output = run_git(dir, &["log", "-1", "--pretty=format:%H%n%s"], None).await?;
+            let mut lines = log_output.lines();
+            Ok(FetchedCommit {
+                sha: lines.next().unwrap_or("unknown").to_string(),
+                message: lines.next().unwrap_or("no commit message").to_string(),
+            })
+        })
+    }
+
+    fn fetch_extras<'a>(
+        &'a self,
+        request: &'a SourceRequest<'a>,
+        source: NurSource,
+    ) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move {
+            let dir = request.dest.to_str().ok_or("Workspace path is not UTF-8")?;
+            let auth = GitAuth::new(&strip_credentials(request.clone_url), request.token);
+
+            if source.submodules {
+                println!("📥 Fetching submodules");
+                run_git(
+                    dir,
+                    &["submodule", "update", "--init", "--recursive", "--depth=1", "--jobs=4"],
+                    auth.as_ref(),
+                )
+                .await?;
+            }
+
+            if source.lfs {
+                println!("📥 Pulling Git LFS objects");
+                run_git(dir, &["lfs", "pull"], auth.as_ref()).await?;
+            }
+
+            Ok(())
+        })
+    }
+}
+
+/// Downloads `/repos/{owner}/{repo}/tarball/{sha}` and extracts it, so the
+/// runtime image doesn't need git at all.
+pub struct TarballFetcher {
+    github: GitHubClient,
+    max_bytes: u64,
+    max_extracted_bytes: u64,
+}
+
+#[derive(Deserialize)]
+struct CommitResponse {
+    sha: String,
+    commit: CommitDetails,
+}
+
+#[derive(Deserialize)]
+struct CommitDetails {
+    message: String,
+}
+
+impl SourceFetcher for TarballFetcher {
+    fn fetch<'a>(
+        &'a self,
+        request: &'a SourceRequest<'a>,
+    ) -> BoxFuture<'a, Result<FetchedCommit, String>> {
+        Box::pin(async move {
+            let SourceRequest {
+                owner,
+                repo,
+                head_sha,
+                token,
+                dest,
+                ..
+            } = *request;
+
+            // The tarball carries no commit metadata, ask for it separately.
+            let path = format!("/repos/{}/{}/commits/{}", owner, repo, head_sha);
+            let commit: CommitResponse = self
+                .github
+                .request(reqwest::Method::GET, &path, token, None)
+                .await
+                .map_err(|e| e.to_string())?;
+
+            let path = format!("/repos/{}/{}/tarball/{}", owner, repo, head_sha);
+            let tarball = self
+                .github
+                .download(&path, token, self.max_bytes)
+                .await
+                .map_err(|e| e.to_string())?;
+            println!("📦 Downloaded {} byte tarball for {}", tarball.len(), head_sha);
+
+            let dest = dest.to_path_buf();
+            let max_extracted_bytes = self.max_extracted_bytes;
+            tokio::task::spawn_blocking(move || {
+                extract_tarball(&tarball[..], &dest, max_extracted_bytes)
+            })
+            .await
+            .map_err(|e| e.to_string())??;
+
+            Ok(FetchedCommit {
+                sha: commit.sha,
+                message: commit.commit.message.lines().next().unwrap_or("").to_string(),
+            })
+        })
+    }
+
+    fn fetch_extras<'a>(
+        &'a self,
+        _request: &'a SourceRequest<'a>,
+        _source: NurSource,
+    ) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async {
+            Err("Submodules and LFS need the project's source_fetch set to git".to_string())
+        })
+    }
+}
+
+/// Unpacks a GitHub tarball into `dest`, dropping the `{owner}-{repo}-{sha}/`
+/// directory every entry is nested in. Entries and symlinks escaping `dest`
+/// are refused, so the checkout matches what git would produce or the build
+/// fails; hard links and special files, which git never produces, are
+/// skipped. Extraction stops past `max_bytes`.
+fn extract_tarball(tarball: impl Read, dest: &Path, max_bytes: u64) -> Result<(), String> {
+    let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(tarball));
+    let mut extracted: u64 = 0;
+    let root = dest.canonicalize().map_err(|e| e.to_string())?;
+
+    for (index, entry) in archive.entries().map_err(|e| e.to_string())?.enumerate() {
+        if index >= MAX_TARBALL_ENTRIES {
+            return Err(format!("Tarball has more than {} entries", MAX_TARBALL_ENTRIES));
+        }
+
+        let mut entry = entry.map_err(|e| e.to_string())?;
+        let entry_path = entry.path().map_err(|e| e.to_string())?.into_owned();
+        let Some(relative) = safe_relative_path(&entry_path) else {
+            return Err(format!("Refusing tarball entry {:?}", entry_path));
+        };
+        if relative.as_os_str().is_empty() {
+            continue;
+        }
+
+        let target = dest.join(&relative);
+        let entry_type = entry.header().entry_type();
+
+        if entry_type.is_dir() {
+            std::fs::create_dir_all(&target).map_err(|e| e.to_string())?;
+        } else if entry_type.is_file() {
+            extracted += entry.header().size().map_err(|e| e.to_string())?;
+            if extracted > max_bytes {
+                return Err(format!("Tarball extracts to more than {} bytes", max_bytes));
+            }
+            if let Some(parent) = target.parent() {
+                std::fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+            }
+            entry.unpack(&target).map_err(|e| e.to_string())?;
+        } else if entry_type.is_symlink() {
+            let link = entry
+                .link_name()
+                .map_err(|e| e.to_string())?
+                .ok_or_else(|| format!("Tarball symlink {:?} has no target", relative))?
+                .into_owned();
+            create_symlink(&root, &target, &link)
+                .map_err(|e| format!("Refusing tarball symlink {:?} -> {:?}: {}", relative, link, e))?;
+        } else if entry_type.is_pax_global_extensions() || entry_type.is_pax_local_extensions() {
+            // GitHub stores the commit SHA in a global pax header.
+        } else {
+            println!("⚠️ Skipping tarball entry {:?} ({:?})", relative, entry_type);
+        }
+    }
+
+    Ok(())
+}
+
+/// Recreates a symlink whose target, read from where the link lives, stays
+/// inside `root`.
+fn create_symlink(root: &Path, path: &Path, link: &Path) -> Result<(), String> {
+    let parent = path.parent().ok_or("no parent directory")?;
+    std::fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+    // Canonical, so links created earlier in the parent path are resolved.
+    let parent = parent.canonicalize().map_err(|e| e.to_string())?;
+
+    if !link_stays_inside(root, &parent, link) {
+        return Err("it points outside the workspace".to_string());
+    }
+    std::os::unix::fs::symlink(link, path).map_err(|e| e.to_string())
+}
+
+/// `..` is resolved lexically, which is only right before anything that
+/// could itself be a link: it is allowed as a prefix of the target only.
+/// `root` and `parent` are canonical.
+fn link_stays_inside(root: &Path, parent: &Path, link: &Path) -> bool {
+    if link.as_os_str().is_empty() {
+        return false;
+    }
+
+    let mut resolved = parent.to_path_buf();
+    let mut descended = false;
+    for component in link.components() {
+        match component {
+            Component::ParentDir if !descended => {
+                if !resolved.pop() {
+                    return false;
+                }
+            }
+            Component::Normal(part) => {
+                descended = true;
+                resolved.push(part);
+            }
+            Component::CurDir => {}
+            _ => return false,
+        }
+    }
+    resolved.starts_with(root)
+}
+
+/// Strips the top-level directory and rejects anything that could land
+/// outside the workspace.
+fn safe_relative_path(path: &Path) -> Option<PathBuf> {
+    let mut components = path.components();
+    match components.next()? {
+        Component::Normal(_) => {}
+        _ => return None,
+    }
+
+    let mut relative = PathBuf::new();
+    for component in components {
+        match component {
+            Component::Normal(part) => relative.push(part),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    Some(relative)
+}
+
+/// Credentials git sends to the repository's host only, so a submodule
+/// hosted elsewhere never sees the installation token.
+struct GitAuth {
+    /// `https://github.com/`, or the GitHub Enterprise host.
+    origin: String,
+    host: String,
+    token: String,
+}
+
+impl GitAuth {
+    fn new(clone_url: &str, token: &str) -> Option<GitAuth> {
+        let (scheme, rest) = clone_url.split_once("://")?;
+        let host = rest.split('/').next().filter(|host| !host.is_empty())?;
+
+        Some(GitAuth {
+            origin: format!("{}://{}/", scheme, host),
+            host: host.to_string(),
+            token: token.to_string(),
+        })
+    }
+
+    /// Config passed through `GIT_CONFIG_*` variables: it never reaches
+    /// `.git/config`, which build containers can read, nor the process list.
+    fn config(&self) -> Vec<(String, String)> {
+        let credentials = BASE64.encode(format!("x-access-token:{}", self.token));
+        vec![
+            (
+                format!("http.{}.extraheader", self.origin),
+                format!("Authorization: Basic {}", credentials),
+            ),
+            // Submodules are often declared with SSH URLs, which we have no
+            // key for.
+            (format!("url.{}.insteadOf", self.origin), format!("git@{}:", self.host)),
+            (format!("url.{}.insteadOf", self.origin), format!("ssh://git@{}/", self.host)),
+        ]
+    }
+}
+
+/// Runs a git command inside `cwd`, turning a non-zero exit into an error
+/// that carries git's stderr. LFS content is only downloaded when asked for
+/// explicitly with `git lfs pull`.
+async fn run_git(cwd: &str, args: &[&str], auth: Option<&GitAuth>) -> Result<String, String> {
+    let mut command = Command::new("git");
+    command
+        .args(args)
+        .current_dir(cwd)
+        .env("GIT_TERMINAL_PROMPT", "0")
+        .env("GIT_LFS_SKIP_SMUDGE", "1");
+
+    if let Some(auth) = auth {
+        let config = auth.config();
+        command.env("GIT_CONFIG_COUNT", config.len().to_string());
+        for (i, (key, value)) in config.into_iter().enumerate() {
+            command
+                .env(format!("GIT_CONFIG_KEY_{}", i), key)
+                .env(format!("GIT_CONFIG_VALUE_{}", i), value);
+        }
+    }
+
+    let output = command.output().await.map_err(|e| e.to_string())?;
+
+    if !output.status.success() {
+        return Err(format!(
+            "git {} failed:\n{}",
+            args.first().unwrap_or(&""),
+            redact_tokens(&String::from_utf8_lossy(&output.stderr))
+        ));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+/// Drops any `user:password@` part from an HTTPS URL.
+fn strip_credentials(url: &str) -> String {
+    match url.split_once("://") {
+        Some((scheme, rest)) => {
+            let authority = &rest[..rest.find('/').unwrap_or(rest.len())];
+            let host_start = authority.rfind('@').map_or(0, |at| at + 1);
+            format!("{}://{}", scheme, &rest[host_start..])
+        }
+        None => url.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use tar::{EntryType, Header};
+
+    /// Entries are written with raw header names, since `tar::Builder`
+    /// itself refuses to create the malicious ones.
+    fn tarball(entries: &[(&str, EntryType, &[u8], &str)]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::fast()));
+        for (name, entry_type, data, link) in entries {
+            let mut header = Header::new_old();
+            header.as_old_mut().name[..name.len()].copy_from_slice(name.as_bytes());
+            header.as_old_mut().linkname[..link.len()].copy_from_slice(link.as_bytes());
+            header.set_entry_type(*entry_type);
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append(&header, *data).unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap()
+    }
+
+    fn extract(entries: &[(&str, EntryType, &[u8], &str)], max_bytes: u64) -> (tempfile::TempDir, Result<(), String>) {
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join("workspace");
+        std::fs::create_dir(&dest).unwrap();
+        let result = extract_tarball(&tarball(entries)[..], &dest, max_bytes);
+        (dir, result)
+    }
+
+    #[test]
+    fn extracts_without_the_top_level_directory() {
+        let (dir, result) = extract(
+            &[
+                ("pax_global_header", EntryType::XGlobalHeader, b"", ""),
+                ("o-r-abc123/", EntryType::Directory, b"", ""),
+                ("o-r-abc123/nurfile.yaml", EntryType::Regular, b"functions: []", ""),
+                ("o-r-abc123/fn/./src/lib.rs", EntryType::Regular, b"fn main() {}", ""),
+            ],
+            1024,
+        );
+
+        result.unwrap();
+        let workspace = dir.path().join("workspace");
+        assert_eq!(std::fs::read(workspace.join("nurfile.yaml")).unwrap(), b"functions: []");
+        assert!(workspace.join("fn/src/lib.rs").is_file());
+    }
+
+    #[test]
+    fn refuses_entries_escaping_the_workspace() {
+        for name in ["o-r-abc123/../../escaped", "../escaped", "/tmp/escaped"] {
+            let (dir, result) = extract(&[(name, EntryType::Regular, b"owned", "")], 1024);
+
+            assert!(result.unwrap_err().starts_with("Refusing tarball entry"), "{}", name);
+            assert!(!dir.path().join("escaped").exists());
+        }
+    }
+
+    #[test]
+    fn recreates_symlinks_inside_the_workspace() {
+        let (dir, result) = extract(
+            &[
+                ("o-r-abc123/shared/config.toml", EntryType::Regular, b"shared", ""),
+                ("o-r-abc123/fn/config.toml", EntryType::Symlink, b"", "../shared/config.toml"),
+                ("o-r-abc123/vendored", EntryType::Symlink, b"", "./shared"),
+                ("o-r-abc123/vendored/inner", EntryType::Symlink, b"", "config.toml"),
+            ],
+            1024,
+        );
+
+        result.unwrap();
+        let workspace = dir.path().join("workspace");
+        assert_eq!(
+            std::fs::read_link(workspace.join("fn/config.toml")).unwrap(),
+            PathBuf::from("../shared/config.toml")
+        );
+        assert_eq!(std::fs::read(workspace.join("fn/config.toml")).unwrap(), b"shared");
+        assert_eq!(std::fs::read(workspace.join("shared/inner")).unwrap(), b"shared");
+    }
+
+    #[test]
+    fn refuses_symlinks_leaving_the_workspace() {
+        let links: [&[(&str, &str)]; 5] = [
+            &[("o-r-abc123/passwd", "/etc/passwd")],
+            &[("o-r-abc123/up", "..")],
+            &[("o-r-abc123/fn/up", "../../workspace-sibling")],
+            &[("o-r-abc123/fn/sneaky", "../fn/../..")],
+            // `here` resolves to the workspace itself, so `here/..` leaves it.
+            &[("o-r-abc123/here", "."), ("o-r-abc123/here/up", "..")],
+        ];
+
+        for entries in links {
+            let entries: Vec<_> = entries
+                .iter()
+                .map(|(name, link)| (*name, EntryType::Symlink, &b""[..], *link))
+                .collect();
+            let (_dir, result) = extract(&entries, 1024);
+
+            let error = result.unwrap_err();
+            assert!(error.starts_with("Refusing tarball symlink"), "{:?}: {}", entries, error);
+        }
+    }
+
+    #[test]
+    fn skips_hard_links_and_special_files() {
+        let (dir, result) = extract(
+            &[
+                ("o-r-abc123/hard", EntryType::Link, b"", "o-r-abc123/lib.rs"),
+                ("o-r-abc123/fifo", EntryType::Fifo, b"", ""),
+                ("o-r-abc123/lib.rs", EntryType::Regular, b"ok", ""),
+            ],
+            1024,
+        );
+
+        result.unwrap();
+        let workspace = dir.path().join("workspace");
+        for skipped in ["hard", "fifo"] {
+            assert!(std::fs::symlink_metadata(workspace.join(skipped)).is_err(), "{}", skipped);
+        }
+        assert!(workspace.join("lib.rs").is_file());
+    }
+
+    #[test]
+    fn stops_past_the_extracted_size_limit() {
+        let (_dir, result) = extract(
+            &[
+                ("o-r-abc123/small", EntryType::Regular, b"12345", ""),
+                ("o-r-abc123/large", EntryType::Regular, b"1234567890", ""),
+            ],
+            10,
+        );
+
+        assert_eq!(result.unwrap_err(), "Tarball extracts to more than 10 bytes");
+    }
+
+    #[test]
+    fn safe_relative_paths() {
+        let safe = |path: &str| safe_relative_path(Path::new(path));
+
+        assert_eq!(safe("o-r-abc123/a/b.rs"), Some(PathBuf::from("a/b.rs")));
+        assert_eq!(safe("o-r-abc123/./a"), Some(PathBuf::from("a")));
+        assert_eq!(safe("o-r-abc123/"), Some(PathBuf::new()));
+        assert_eq!(safe("o-r-abc123/a/../../b"), None);
+        assert_eq!(safe("./o-r-abc123/a"), None);
+        assert_eq!(safe("/o-r-abc123/a"), None);
+        assert_eq!(safe(".."), None);
+    }
+}
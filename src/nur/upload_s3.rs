@@ -3,19 +3,12 @@ use aws_config::{meta::region::RegionProviderChain, BehaviorVersion};
 use aws_sdk_s3::config::Credentials;
 use aws_sdk_s3::primitives::ByteStream;
 use aws_sdk_s3::Client;
+use std::collections::HashMap;
 use std::{env, path::Path};
 use tokio::fs::File;
 use tokio::io::AsyncReadExt;
 
-pub async fn upload_to_s3(
-    bucket: &str,
-    key: &str,
-    file_path: &Path,
-) -> Result<(), Box<dyn std::error::Error>> {
-    if !file_path.exists() {
-        return Err(format!("File does not exist: {}", file_path.display()).into());
-    }
-
+async fn s3_client() -> Result<Client, Box<dyn std::error::Error>> {
     let access_key =
         env::var("AWS_ACCESS_KEY_ID").map_err(|_| "Missing AWS_ACCESS_KEY_ID in .env")?;
     let secret_key =
@@ -38,7 +31,29 @@ pub async fn upload_to_s3(
         .load()
         .await;
 
-    let client = Client::new(&config);
+    Ok(Client::new(&config))
+}
+
+pub async fn upload_to_s3(
+    bucket: &str,
+    key: &str,
+    file_path: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    upload_to_s3_with_metadata(bucket, key, file_path, HashMap::new()).await
+}
+
+/// Uploads with user metadata, returned later by `head_s3_object`.
+pub async fn upload_to_s3_with_metadata(
+    bucket: &str,
+    key: &str,
+    file_path: &Path,
+    metadata: HashMap<String, String>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if !file_path.exists() {
+        return Err(format!("File does not exist: {}", file_path.display()).into());
+    }
+
+    let client = s3_client().await?;
 
     let mut file = File::open(file_path).await?;
     let mut buffer = Vec::new();
@@ -49,6 +64,7 @@ pub async fn upload_to_s3(
         .put_object()
         .bucket(bucket)
         .key(key)
+        .set_metadata(Some(metadata))
         .body(ByteStream::from(buffer))
         .send()
         .await?;
@@ -56,3 +72,55 @@ pub async fn upload_to_s3(
     println!("✅ Uploaded to S3: s3://{}/{}", bucket, key);
     Ok(())
 }
+
+pub async fn delete_from_s3(bucket: &str, key: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let client = s3_client().await?;
+
+    println!("🗑️ Deleting from S3: s3://{}/{}", bucket, key);
+    client.delete_object().bucket(bucket).key(key).send().await?;
+
+    Ok(())
+}
+
+/// Size and user metadata of an object that exists.
+pub struct S3Object {
+    pub size: u64,
+    pub metadata: HashMap<String, String>,
+}
+
+/// `None` when there is no object under `key`.
+pub async fn head_s3_object(
+    bucket: &str,
+    key: &str,
+) -> Result<Option<S3Object>, Box<dyn std::error::Error>> {
+    let client = s3_client().await?;
+
+    match client.head_object().bucket(bucket).key(key).send().await {
+        Ok(head) => Ok(Some(S3Object {
+            size: head.content_length().unwrap_or_default().max(0) as u64,
+            metadata: head.metadata().cloned().unwrap_or_default(),
+        })),
+        Err(e) if e.as_service_error().is_some_and(|e| e.is_not_found()) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Server-side copy within the bucket, metadata included.
+pub async fn copy_in_s3(
+    bucket: &str,
+    from_key: &str,
+    to_key: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let client = s3_client().await?;
+
+    println!("☁️ Copying in S3: s3://{}/{} -> {}", bucket, from_key, to_key);
+    client
+        .copy_object()
+        .bucket(bucket)
+        .copy_source(format!("{}/{}", bucket, from_key))
+        .key(to_key)
+        .send()
+        .await?;
+
+    Ok(())
+}
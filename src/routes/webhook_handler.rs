@@ -1,134 +1,634 @@
 use crate::app_state::AppState;
-use crate::github::jwt::create_jwt;
-use crate::github::models::GitHubPushEvent;
-use crate::nur::build::run_nur_build;
+use crate::github::models::{
+    CheckSuite, GitHubCheckRunEvent, GitHubCheckSuiteEvent, GitHubDeleteEvent,
+    GitHubInstallationEvent, GitHubInstallationRepositoriesEvent, GitHubPullRequestEvent,
+    GitHubPushEvent, GitHubRepositoryEvent, Installation, InstallationRepository, Repository,
+    WebhookEvent,
+};
+use crate::github::client::GitHubError;
+use crate::github::repos::get_branch_head;
+use crate::github::tokens::{get_installation_token, InstallationAuth};
+use crate::nur::cancel::Stopping;
+use crate::nur::changes::paths_from_commits;
+use crate::nur::preview::{cleanup_branch_previews, cleanup_preview, PreviewRepo};
+use crate::nur::queue::{enqueue_build, BuildJob, BuildKind};
+use crate::supabase::crud::{
+    archive_installation_projects, archive_repo_projects, claim_delivery,
+    get_previous_deployed_commit, get_project_id, get_supabase_client, release_delivery,
+    rename_repo_project, upsert_repo_project,
+};
 use crate::utils::verify_signature;
 
 use axum::body::to_bytes;
 use axum::body::Body;
 use axum::extract::Request;
 use axum::http::HeaderMap;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
 use axum::{extract::State, http::StatusCode};
+use postgrest::Postgrest;
+use serde_json::json;
 use std::sync::Arc;
+use uuid::Uuid;
+
+const PULL_REQUEST_BUILD_ACTIONS: [&str; 3] = ["opened", "synchronize", "reopened"];
+
+/// How long preview cleanup waits for the builds it cancelled.
+const CANCELLED_BUILDS_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10 * 60);
 
 pub async fn webhook_handler(
     headers: HeaderMap,
     State(state): State<Arc<AppState>>,
     req: Request<Body>,
-) -> StatusCode {
-    let event_type = headers.get("x-github-event").map(|h| h.to_str().unwrap_or(""));
-    if event_type != Some("push") {
-        println!("🔁 Ignoring event type: {event_type:?}");
-        return StatusCode::OK;
-    }
-
+) -> Response {
     let (_parts, body) = req.into_parts();
     let body_bytes = to_bytes(body, usize::MAX).await.unwrap();
-    let _body_str = String::from_utf8_lossy(&body_bytes);
 
-    // ✅ 1. Verificar firma
-    if let Some(sig) = headers.get("X-Hub-Signature-256") {
-        let sig_str = sig.to_str().unwrap_or("");
-        if !verify_signature(sig_str, &body_bytes, &state.webhook_secret) {
-            println!("❌ Invalid signature");
-            return StatusCode::UNAUTHORIZED;
-        }
+    // ✅ 1. Verificar firma, obligatoria en todas las entregas
+    let Some(sig) = headers.get("X-Hub-Signature-256") else {
+        println!("❌ Missing signature");
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+    let sig_str = sig.to_str().unwrap_or("");
+    if !verify_signature(sig_str, &body_bytes, &state.webhook_secrets) {
+        println!("❌ Invalid signature");
+        return StatusCode::UNAUTHORIZED.into_response();
     }
 
-    // ✅ 2. Parsear evento
-    let event: GitHubPushEvent = match serde_json::from_slice(&body_bytes) {
-        Ok(e) => e,
+    let event_type = headers
+        .get("x-github-event")
+        .and_then(|h| h.to_str().ok())
+        .unwrap_or("")
+        .to_string();
+    let event = match WebhookEvent::parse(&event_type, &body_bytes) {
+        Ok(Some(event)) => event,
+        Ok(None) => {
+            println!("🔁 Ignoring event type: {event_type:?}");
+            return StatusCode::OK.into_response();
+        }
         Err(e) => {
-            println!("❌ Invalid JSON payload: {:?}", e);
-            return StatusCode::BAD_REQUEST;
+            println!("❌ Invalid {} payload: {:?}", event_type, e);
+            return StatusCode::BAD_REQUEST.into_response();
+        }
+    };
+
+    let delivery_id = headers
+        .get("x-github-delivery")
+        .and_then(|h| h.to_str().ok())
+        .map(str::to_string);
+    if let Some(delivery_id) = &delivery_id {
+        if is_duplicate_delivery(&state, delivery_id, &event_type).await {
+            println!("🔁 Ignoring duplicate delivery: {delivery_id}");
+            return StatusCode::OK.into_response();
+        }
+    }
+
+    let response = handle_event(&state, event).await;
+
+    // GitHub redelivers on 5xx; that redelivery must not look like a duplicate.
+    if response.status().is_server_error() {
+        if let Some(delivery_id) = &delivery_id {
+            forget_delivery(delivery_id).await;
+        }
+    }
+    response
+}
+
+async fn handle_event(state: &Arc<AppState>, event: WebhookEvent) -> Response {
+    // ✅ 2. Armar el job según el evento
+    let job = match event {
+        WebhookEvent::Push(event) => push_job(event),
+        WebhookEvent::PullRequest(event) => pull_request_job(state, event),
+        WebhookEvent::CheckRun(event) => check_run_job(state, event).await,
+        WebhookEvent::CheckSuite(event) => check_suite_job(state, event).await,
+        WebhookEvent::Installation(event) => installation_event(event).await,
+        WebhookEvent::InstallationRepositories(event) => {
+            installation_repositories_event(event).await
         }
+        WebhookEvent::Repository(event) => repository_event(event).await,
+        WebhookEvent::Delete(event) => delete_event(state, event),
     };
+    let job = match job {
+        Ok(Some(job)) => job,
+        Ok(None) => return StatusCode::OK.into_response(),
+        Err(status) => return status.into_response(),
+    };
+
+    println!("📦 Repo ID: {}", job.repo_id);
+
+    // ✅ 3. Encolar el build, los workers se encargan del resto
+    let build_id = job.id;
+
+    if let Err(e) = enqueue_build(state, job).await {
+        println!("❌ Failed to enqueue build {}: {}", build_id, e);
+        return StatusCode::SERVICE_UNAVAILABLE.into_response();
+    }
 
-    let repo_id = event.repository.id;
-    println!("📦 Repo ID: {}", repo_id);
+    println!("📥 Build {} queued", build_id);
+    (StatusCode::ACCEPTED, Json(json!({ "build_id": build_id }))).into_response()
+}
+
+fn push_job(event: GitHubPushEvent) -> Result<Option<BuildJob>, StatusCode> {
+    if event.is_deletion() {
+        // The matching `delete` event does the cleanup.
+        println!("🔁 Ignoring push deleting {}", event.git_ref);
+        return Ok(None);
+    }
     println!("✅ Push event: {:?}", event.repository.full_name);
 
-    // ✅ 3. Crear JWT
-    let jwt = create_jwt(&state.app_id, &state.encoding_key);
-
-    // ✅ 4. Obtener token de instalación
-    let token_res = state
-        .client
-        .post(format!(
-            "https://api.github.com/app/installations/{}/access_tokens",
-            event.installation.id
-        ))
-        .bearer_auth(jwt)
-        .header("Accept", "application/vnd.github+json")
-        .header("User-Agent", "nur-wasm-builder")
-        .send()
-        .await
-        .unwrap();
-
-    let token_json: serde_json::Value = token_res.json().await.unwrap();
-    let token = token_json["token"].as_str().unwrap();
-
-    let check_run_id = match crate::github::checks::create_check_run(
-        token,
-        &event.repository.owner.name,
-        &event.repository.name,
-        "Nur functions compilation",
-        &event.after,
-    ).await {
-        Ok(check_run_id) => {
-            println!("✅ Check run created with ID: {}", check_run_id);
-            check_run_id
+    // A forced push's commits don't describe what changed since `before`.
+    let changed_paths = if event.forced {
+        None
+    } else {
+        paths_from_commits(&event.commits)
+    };
+
+    Ok(Some(BuildJob {
+        id: Uuid::new_v4(),
+        repo_id: event.repository.id,
+        owner: event.repository.owner.login,
+        repo_name: event.repository.name,
+        clone_url: event.repository.clone_url,
+        head_sha: event.after,
+        git_ref: event.git_ref,
+        default_branch: event.repository.default_branch,
+        installation_id: event.installation.id,
+        kind: BuildKind::Push,
+        before: Some(event.before),
+        changed_paths,
+    }))
+}
+
+fn pull_request_job(
+    state: &Arc<AppState>,
+    event: GitHubPullRequestEvent,
+) -> Result<Option<BuildJob>, StatusCode> {
+    if event.action == "closed" {
+        let pr = event.number;
+        println!("🧹 Pull request #{} closed, cleaning up previews", pr);
+        let repo = preview_repo(state, &event.repository, &event.installation);
+        let stopping = state.builds_in_flight.cancel_key(repo.id, &format!("pull/{}", pr));
+        tokio::spawn(async move {
+            wait_for_cancelled_builds(stopping, &format!("closed pull request #{}", pr)).await;
+            if let Err(e) = cleanup_preview(&repo, pr).await {
+                println!("❌ Failed to clean up previews for PR #{}: {}", pr, e);
+            }
+        });
+        return Ok(None);
+    }
+
+    if !PULL_REQUEST_BUILD_ACTIONS.contains(&event.action.as_str()) {
+        println!("🔁 Ignoring pull_request action: {}", event.action);
+        return Ok(None);
+    }
+    println!(
+        "✅ Pull request #{} event: {:?}",
+        event.number, event.repository.full_name
+    );
+
+    // The head may live in a fork, but GitHub exposes every PR head
+    // in the base repository too, so fetching by SHA from there works.
+    Ok(Some(BuildJob {
+        id: Uuid::new_v4(),
+        repo_id: event.repository.id,
+        owner: event.repository.owner.login,
+        repo_name: event.repository.name,
+        clone_url: event.repository.clone_url,
+        head_sha: event.pull_request.head.sha,
+        git_ref: format!("refs/heads/{}", event.pull_request.head.git_ref),
+        default_branch: event.repository.default_branch,
+        installation_id: event.installation.id,
+        kind: BuildKind::PullRequest {
+            number: event.number,
+            base_ref: event.pull_request.base.git_ref,
         },
+        before: None,
+        changed_paths: None,
+    }))
+}
+
+/// The "Re-run" button on a single check run, and our own requested actions.
+async fn check_run_job(
+    state: &AppState,
+    event: GitHubCheckRunEvent,
+) -> Result<Option<BuildJob>, StatusCode> {
+    let suite = &event.check_run.check_suite;
+    let head_sha = &event.check_run.head_sha;
+
+    match event.action.as_str() {
+        "rerequested" => {
+            println!("🔁 Re-run requested for check run {}", event.check_run.id);
+            rerun_job(state, &event.repository, &event.installation, suite, head_sha).await
+        }
+        "requested_action" => {
+            let identifier = event
+                .requested_action
+                .as_ref()
+                .map(|a| a.identifier.as_str())
+                .unwrap_or("");
+
+            match identifier {
+                "rebuild" => {
+                    println!("🔁 Rebuild requested for {}", head_sha);
+                    rerun_job(state, &event.repository, &event.installation, suite, head_sha).await
+                }
+                "rollback" => rollback_job(&event).await,
+                other => {
+                    println!("🔁 Ignoring unknown requested action: {other:?}");
+                    Ok(None)
+                }
+            }
+        }
+        other => {
+            println!("🔁 Ignoring check_run action: {other}");
+            Ok(None)
+        }
+    }
+}
+
+/// The "Re-run all checks" button.
+async fn check_suite_job(
+    state: &AppState,
+    event: GitHubCheckSuiteEvent,
+) -> Result<Option<BuildJob>, StatusCode> {
+    if event.action != "rerequested" {
+        println!("🔁 Ignoring check_suite action: {}", event.action);
+        return Ok(None);
+    }
+
+    println!("🔁 Re-run requested for check suite {}", event.check_suite.id);
+    let suite = &event.check_suite;
+    rerun_job(state, &event.repository, &event.installation, suite, &suite.head_sha).await
+}
+
+/// Looks up where the suite's branch points in this repository before
+/// deciding how to build it again, see `rebuild_job`.
+async fn rerun_job(
+    state: &AppState,
+    repository: &Repository,
+    installation: &Installation,
+    suite: &CheckSuite,
+    head_sha: &str,
+) -> Result<Option<BuildJob>, StatusCode> {
+    let branch_head = match (suite.pull_requests.is_empty(), &suite.head_branch) {
+        (true, Some(branch)) => {
+            let lookup = async {
+                let token = get_installation_token(state, installation.id)
+                    .await
+                    .map_err(GitHubError::Auth)?;
+                let owner = &repository.owner.login;
+                get_branch_head(&state.github, &token, owner, &repository.name, branch).await
+            };
+            lookup.await.map_err(|e| {
+                println!("❌ Failed to look up branch '{}': {}", branch, e);
+                StatusCode::SERVICE_UNAVAILABLE
+            })?
+        }
+        _ => None,
+    };
+
+    Ok(rebuild_job(repository, installation, suite, head_sha, branch_head.as_deref()))
+}
+
+/// Builds `head_sha` again. Suites with a pull request are rebuilt as that
+/// pull request, so a re-run never deploys what was only a PR build. The
+/// others are rebuilt like a push to their head branch, but only when
+/// `head_sha` is what that branch points to in this repository right now
+/// (`branch_head`): a fork's suite carries the fork's branch name, which
+/// may well be `main`, and an older commit would replace what is deployed.
+/// Deploying an older commit on purpose is what Roll back is for.
+fn rebuild_job(
+    repository: &Repository,
+    installation: &Installation,
+    suite: &CheckSuite,
+    head_sha: &str,
+    branch_head: Option<&str>,
+) -> Option<BuildJob> {
+    let (git_ref, kind) = match (suite.pull_requests.first(), &suite.head_branch) {
+        (Some(pr), _) => (
+            format!("refs/heads/{}", pr.head.git_ref),
+            BuildKind::PullRequest {
+                number: pr.number,
+                base_ref: pr.base.git_ref.clone(),
+            },
+        ),
+        (None, Some(branch)) if branch_head == Some(head_sha) => {
+            (format!("refs/heads/{}", branch), BuildKind::Push)
+        }
+        (None, Some(branch)) => {
+            println!(
+                "🔁 Ignoring re-run of {}: not the head of '{}' in {}",
+                head_sha, branch, repository.full_name
+            );
+            return None;
+        }
+        (None, None) => {
+            println!("🔁 Ignoring re-run of {}: no branch or pull request to build it as", head_sha);
+            return None;
+        }
+    };
+
+    Some(build_job(repository, installation, head_sha, git_ref, kind))
+}
+
+fn build_job(
+    repository: &Repository,
+    installation: &Installation,
+    head_sha: &str,
+    git_ref: String,
+    kind: BuildKind,
+) -> BuildJob {
+    BuildJob {
+        id: Uuid::new_v4(),
+        repo_id: repository.id,
+        owner: repository.owner.login.clone(),
+        repo_name: repository.name.clone(),
+        clone_url: repository.clone_url.clone(),
+        head_sha: head_sha.to_string(),
+        git_ref,
+        default_branch: repository.default_branch.clone(),
+        installation_id: installation.id,
+        kind,
+        before: None,
+        changed_paths: None,
+    }
+}
+
+/// Rolling back means rebuilding and deploying the last commit of the same
+/// branch that deployed successfully before this one.
+async fn rollback_job(event: &GitHubCheckRunEvent) -> Result<Option<BuildJob>, StatusCode> {
+    let suite = &event.check_run.check_suite;
+    let Some(branch) = suite.head_branch.as_deref() else {
+        println!("🔁 Ignoring rollback without a head branch");
+        return Ok(None);
+    };
+
+    let client = supabase_or_unavailable()?;
+    let previous = match get_project_id(&client, &event.repository.id.to_string()).await {
+        Ok(project_id) => {
+            get_previous_deployed_commit(&client, &project_id, branch, &event.check_run.head_sha)
+                .await
+        }
+        Err(e) => Err(e),
+    };
+
+    match previous {
+        Ok(Some(sha)) => {
+            println!("⏪ Rolling back '{}' to {}", branch, sha);
+            // Only production deploys offer a rollback, so it is a push
+            // build even if the branch has a pull request open.
+            Ok(Some(build_job(
+                &event.repository,
+                &event.installation,
+                &sha,
+                format!("refs/heads/{}", branch),
+                BuildKind::Push,
+            )))
+        }
+        Ok(None) => {
+            println!("⏪ Nothing to roll back to on '{}'", branch);
+            Ok(None)
+        }
         Err(e) => {
-            println!("❌ Failed to create check run: {:?}", e);
-            return StatusCode::INTERNAL_SERVER_ERROR;
+            println!("❌ Failed to look up previous deployment: {}", e);
+            Err(StatusCode::SERVICE_UNAVAILABLE)
+        }
+    }
+}
+
+/// Keeps a project per repository the app is installed on: created on
+/// install, archived on uninstall.
+async fn installation_event(event: GitHubInstallationEvent) -> Result<Option<BuildJob>, StatusCode> {
+    let installation_id = event.installation.id;
+    let client = supabase_or_unavailable()?;
+
+    let result = match event.action.as_str() {
+        "created" => link_projects(&client, installation_id, &event.repositories).await,
+        "deleted" => {
+            println!("🗄️ Installation {} removed, archiving its projects", installation_id);
+            archive_installation_projects(&client, installation_id)
+                .await
+                .map(|_| ())
+        }
+        other => {
+            println!("🔁 Ignoring installation action: {other}");
+            Ok(())
         }
     };
 
-    // ✅ 5. URL para clonar la repo
-    let clone_url = event
-        .repository
-        .clone_url
-        .replace("https://", &format!("https://x-access-token:{}@", token));
-
-    // ✅ 6. Ejecutar build
-    let status_code: StatusCode;
-    let conclusion: &str;
-    let mut summary: String;
-
-    match run_nur_build(&clone_url, &repo_id).await {
-        Ok(functions) => {
-            status_code = StatusCode::OK;
-            conclusion = "success";
-            summary = "Functions compiled successfully! Summary:\n".to_string();
-            for func in functions {
-                summary.push_str(&format!("- Function: {}, Dir: {}\n", func.name, func.directory));
-            }
-            println!("✅ Build completed successfully.");
+    result.map(|_| None).map_err(|e| {
+        println!("❌ Failed to sync projects for installation {}: {}", installation_id, e);
+        StatusCode::SERVICE_UNAVAILABLE
+    })
+}
+
+/// Repositories added to or removed from an existing installation.
+async fn installation_repositories_event(
+    event: GitHubInstallationRepositoriesEvent,
+) -> Result<Option<BuildJob>, StatusCode> {
+    let installation_id = event.installation.id;
+    let client = supabase_or_unavailable()?;
+
+    let mut result = link_projects(&client, installation_id, &event.repositories_added).await;
+
+    if result.is_ok() && !event.repositories_removed.is_empty() {
+        let ids: Vec<u64> = event.repositories_removed.iter().map(|r| r.id).collect();
+        for repo in &event.repositories_removed {
+            println!("🗄️ Archiving project for {}", repo.full_name);
         }
+        result = archive_repo_projects(&client, &ids).await.map(|_| ());
+    }
+
+    result.map(|_| None).map_err(|e| {
+        println!("❌ Failed to sync projects for installation {}: {}", installation_id, e);
+        StatusCode::SERVICE_UNAVAILABLE
+    })
+}
+
+/// Renames and transfers keep the same repository id, so the project stays
+/// linked and only its name is refreshed.
+async fn repository_event(event: GitHubRepositoryEvent) -> Result<Option<BuildJob>, StatusCode> {
+    let repo = &event.repository;
+    let client = supabase_or_unavailable()?;
+
+    let result = match event.action.as_str() {
+        "renamed" | "transferred" => {
+            println!("✏️ Repository {} {}", repo.full_name, event.action);
+            rename_repo_project(&client, repo.id, &repo.name).await
+        }
+        "deleted" => {
+            println!("🗄️ Repository {} deleted, archiving its project", repo.full_name);
+            archive_repo_projects(&client, &[repo.id]).await
+        }
+        other => {
+            println!("🔁 Ignoring repository action: {other}");
+            return Ok(None);
+        }
+    };
+
+    result.map(|_| None).map_err(|e| {
+        println!("❌ Failed to update project for {}: {}", repo.full_name, e);
+        StatusCode::SERVICE_UNAVAILABLE
+    })
+}
+
+/// A deleted branch stops its in-flight builds and loses its previews.
+fn delete_event(
+    state: &Arc<AppState>,
+    event: GitHubDeleteEvent,
+) -> Result<Option<BuildJob>, StatusCode> {
+    if event.ref_type != "branch" {
+        println!("🔁 Ignoring deleted {}: {}", event.ref_type, event.git_ref);
+        return Ok(None);
+    }
+
+    let repo = preview_repo(state, &event.repository, &event.installation);
+    let branch = event.git_ref;
+    println!("🧹 Branch '{}' deleted in {}", branch, event.repository.full_name);
+
+    let stopping = state
+        .builds_in_flight
+        .cancel_branch(repo.id, &format!("refs/heads/{}", branch));
+    tokio::spawn(async move {
+        wait_for_cancelled_builds(stopping, &format!("deleted branch '{}'", branch)).await;
+        if let Err(e) = cleanup_branch_previews(&repo, &branch).await {
+            println!("❌ Failed to clean up previews for branch '{}': {}", branch, e);
+        }
+    });
+
+    Ok(None)
+}
+
+fn preview_repo(
+    state: &Arc<AppState>,
+    repository: &Repository,
+    installation: &Installation,
+) -> PreviewRepo {
+    PreviewRepo {
+        id: repository.id,
+        owner: repository.owner.login.clone(),
+        name: repository.name.clone(),
+        github: state.github.clone(),
+        auth: InstallationAuth::new(state.clone(), installation.id),
+    }
+}
+
+/// A preview build still uploading would otherwise record its preview after
+/// the cleanup, so cleanups wait for the builds they cancelled.
+async fn wait_for_cancelled_builds(stopping: Stopping, what: &str) {
+    if tokio::time::timeout(CANCELLED_BUILDS_TIMEOUT, stopping.finished())
+        .await
+        .is_err()
+    {
+        println!("⚠️ Builds of {} are still running, cleaning up anyway", what);
+    }
+}
+
+async fn link_projects(
+    client: &Postgrest,
+    installation_id: u64,
+    repositories: &[InstallationRepository],
+) -> Result<(), String> {
+    for repo in repositories {
+        println!("🔗 Linking project for {}", repo.full_name);
+        upsert_repo_project(client, repo.id, &repo.name, installation_id).await?;
+    }
+    Ok(())
+}
+
+fn supabase_or_unavailable() -> Result<Postgrest, StatusCode> {
+    get_supabase_client().map_err(|e| {
+        println!("❌ Supabase error: {}", e);
+        StatusCode::SERVICE_UNAVAILABLE
+    })
+}
+
+/// Records the delivery and reports whether it was already seen inside the
+/// dedup window. Supabase errors are logged and treated as "not seen":
+/// building twice is better than dropping a push.
+async fn is_duplicate_delivery(state: &AppState, delivery_id: &str, event_type: &str) -> bool {
+    let client = match get_supabase_client() {
+        Ok(client) => client,
         Err(e) => {
-            status_code = StatusCode::INTERNAL_SERVER_ERROR;
-            conclusion = "failure";
-            summary = format!("Build failed: {:?}", e);
-            println!("❌ Build error: {:?}", e);
-        }
-    }
-
-    match crate::github::checks::complete_check_run(
-        token,
-        &event.repository.owner.name,
-        &event.repository.name,
-        check_run_id,
-        conclusion,
-        &summary,
-    ).await {
-        Ok(_) => {
-            println!("🔔 Check completion notified: conclusion={conclusion}");
-        },
+            println!("⚠️ Skipping delivery dedup: {}", e);
+            return false;
+        }
+    };
+
+    let since = (chrono::Utc::now() - state.dedup_window).to_rfc3339();
+    match claim_delivery(&client, delivery_id, event_type, &since).await {
+        Ok(claimed) => !claimed,
         Err(e) => {
-            println!("🔔❌ Failed to notify check completion for check_run_id={check_run_id}: {e:?}");
+            println!("⚠️ Failed to record delivery {}: {}", delivery_id, e);
+            false
         }
+    }
+}
+
+async fn forget_delivery(delivery_id: &str) {
+    let result = match get_supabase_client() {
+        Ok(client) => release_delivery(&client, delivery_id).await,
+        Err(e) => Err(e),
     };
+    if let Err(e) = result {
+        println!("⚠️ Failed to forget delivery {}, its redelivery will be dropped: {}", delivery_id, e);
+    }
+}
 
-    status_code
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check_suite_event(fixture: &str) -> GitHubCheckSuiteEvent {
+        match WebhookEvent::parse("check_suite", fixture.as_bytes()) {
+            Ok(Some(WebhookEvent::CheckSuite(event))) => event,
+            _ => panic!("expected a check_suite event"),
+        }
+    }
+
+    fn rebuild(event: &GitHubCheckSuiteEvent, branch_head: Option<&str>) -> Option<BuildJob> {
+        let suite = &event.check_suite;
+        rebuild_job(&event.repository, &event.installation, suite, &suite.head_sha, branch_head)
+    }
+
+    #[test]
+    fn fork_suite_reruns_build_nothing() {
+        let event = check_suite_event(include_str!("../github/fixtures/check_suite_rerequested_fork.json"));
+        assert_eq!(event.check_suite.head_branch.as_deref(), Some("main"));
+
+        // The repository's own `main` points elsewhere, or is gone.
+        assert!(rebuild(&event, Some("ec26c3e57ca3a959ca5aad62de7213c562f8c821")).is_none());
+        assert!(rebuild(&event, None).is_none());
+    }
+
+    #[test]
+    fn branch_reruns_only_build_the_branch_head() {
+        let mut event = check_suite_event(include_str!("../github/fixtures/check_suite_rerequested.json"));
+        event.check_suite.pull_requests.clear();
+        let head_sha = event.check_suite.head_sha.clone();
+
+        // An older commit of the branch would replace what is deployed.
+        assert!(rebuild(&event, Some("0000000000000000000000000000000000000000")).is_none());
+        assert!(rebuild(&event, None).is_none());
+
+        let job = rebuild(&event, Some(&head_sha)).expect("a rebuild job");
+        assert_eq!(job.git_ref, "refs/heads/feature/greeting");
+        assert!(matches!(job.kind, BuildKind::Push));
+    }
+
+    #[test]
+    fn pull_request_suite_reruns_build_the_pull_request() {
+        let event = check_suite_event(include_str!("../github/fixtures/check_suite_rerequested.json"));
+
+        let job = rebuild(&event, None).expect("a rebuild job");
+        assert_eq!(job.git_ref, "refs/heads/feature/greeting");
+        assert_eq!(job.head_sha, "ec26c3e57ca3a959ca5aad62de7213c562f8c821");
+        match job.kind {
+            BuildKind::PullRequest { number, base_ref } => {
+                assert_eq!(number, 2);
+                assert_eq!(base_ref, "main");
+            }
+            BuildKind::Push => panic!("a pull request re-run must not be built as a push"),
+        }
+    }
 }
@@ -26,6 +26,8 @@ pub async fn test_supabase() -> Result<String, String> {
     Ok(body)
 }
 
+/// Records a build and returns its ID, read back from the inserted row so
+/// concurrent builds can't pick up each other's.
 pub async fn insert_project_build(
     client: &Postgrest,
     project_id: &str,
@@ -47,7 +49,21 @@ pub async fn insert_project_build(
         .await
         .map_err(|e| e.to_string())?;
 
-    response.text().await.map_err(|e| e.to_string())
+    let status = response.status();
+    let text = response.text().await.map_err(|e| e.to_string())?;
+    if !status.is_success() {
+        return Err(format!("project_builds insert returned {}: {}", status, text));
+    }
+    let json: Value = serde_json::from_str(&text).map_err(|e| e.to_string())?;
+
+    match json
+        .get(0)
+        .and_then(|entry| entry.get("id"))
+        .and_then(|id| id.as_str())
+    {
+        Some(id) => Ok(id.to_string()),
+        None => Err(format!("No build ID in project_builds response: {}", text)),
+    }
 }
 
 pub async fn insert_if_not_exists(
@@ -71,16 +87,24 @@ pub async fn insert_if_not_exists(
     response.text().await.map_err(|e| e.to_string())
 }
 
+/// `content_hash` ties the deployment to its artifact in the build cache,
+/// `cache/{content_hash}.wasm.zst`.
 pub async fn insert_function_deployed(
     client: &Postgrest,
     function_id: &str,
     build_id: &str,
     status: &str,
+    wasm_size: u64,
+    compressed_size: u64,
+    content_hash: Option<&str>,
 ) -> Result<String, String> {
     let payload = json!([{
         "function_id": function_id,
         "project_build_id": build_id,
         "status": status,
+        "wasm_size": wasm_size,
+        "compressed_size": compressed_size,
+        "content_hash": content_hash,
     }]);
 
     let response = client
@@ -93,11 +117,16 @@ pub async fn insert_function_deployed(
     response.text().await.map_err(|e| e.to_string())
 }
 
-pub async fn get_project_id(client: &Postgrest, github_repo_id: &str) -> Result<String, String> {
+/// How the project wants its source fetched (`git` or `tarball`), `None`
+/// when it never chose.
+pub async fn get_project_source_fetch(
+    client: &Postgrest,
+    project_id: &str,
+) -> Result<Option<String>, String> {
     let response = client
         .from("projects")
-        .select("id")
-        .eq("github_repo_id", github_repo_id)
+        .select("source_fetch")
+        .eq("id", project_id)
         .limit(1)
         .execute()
         .await
@@ -106,24 +135,33 @@ pub async fn get_project_id(client: &Postgrest, github_repo_id: &str) -> Result<
     let text = response.text().await.map_err(|e| e.to_string())?;
     let json: Value = serde_json::from_str(&text).map_err(|e| e.to_string())?;
 
-    match json
+    Ok(json
         .get(0)
-        .and_then(|entry| entry.get("id"))
-        .and_then(|id| id.as_str())
-    {
-        Some(id) => Ok(id.to_string()),
-        None => Err(format!(
+        .and_then(|entry| entry.get("source_fetch"))
+        .and_then(|method| method.as_str())
+        .map(|method| method.to_string()))
+}
+
+pub async fn get_project_id(client: &Postgrest, github_repo_id: &str) -> Result<String, String> {
+    find_project_id(client, github_repo_id).await?.ok_or_else(|| {
+        format!(
             "Project ID not found for github_repo_id: {}",
             github_repo_id
-        )),
-    }
+        )
+    })
 }
 
-pub async fn get_build_id(client: &Postgrest) -> Result<String, String> {
+/// The active project linked to the repository, `None` if there is none or
+/// it was archived.
+pub async fn find_project_id(
+    client: &Postgrest,
+    github_repo_id: &str,
+) -> Result<Option<String>, String> {
     let response = client
-        .from("project_builds")
+        .from("projects")
         .select("id")
-        .order("created_at.desc")
+        .eq("github_repo_id", github_repo_id)
+        .is("archived_at", "null")
         .limit(1)
         .execute()
         .await
@@ -132,14 +170,118 @@ pub async fn get_build_id(client: &Postgrest) -> Result<String, String> {
     let text = response.text().await.map_err(|e| e.to_string())?;
     let json: Value = serde_json::from_str(&text).map_err(|e| e.to_string())?;
 
-    match json
+    Ok(json
         .get(0)
         .and_then(|entry| entry.get("id"))
         .and_then(|id| id.as_str())
-    {
-        Some(id) => Ok(id.to_string()),
-        None => Err("Project ID not found".to_string()),
+        .map(|id| id.to_string()))
+}
+
+/// Creates the project for a repository the app was installed on, or links
+/// and un-archives the existing one. `name` is only used for a new project:
+/// reinstalling the app must not undo a rename done in Nur.
+pub async fn upsert_repo_project(
+    client: &Postgrest,
+    github_repo_id: u64,
+    name: &str,
+    installation_id: u64,
+) -> Result<String, String> {
+    if let Some(body) = relink_repo_project(client, github_repo_id, installation_id).await? {
+        return Ok(body);
     }
+
+    let payload = json!([{
+        "github_repo_id": github_repo_id.to_string(),
+        "name": name,
+        "installation_id": installation_id,
+    }]);
+
+    let response = client
+        .from("projects")
+        .insert(payload.to_string())
+        .execute()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    // 409: another delivery created it in the meantime, link that one.
+    if response.status().as_u16() == 409 {
+        return relink_repo_project(client, github_repo_id, installation_id)
+            .await?
+            .ok_or_else(|| format!("Project for repository {} vanished", github_repo_id));
+    }
+    response.text().await.map_err(|e| e.to_string())
+}
+
+/// Points an existing project at `installation_id` and un-archives it.
+/// `None` when the repository has no project yet.
+async fn relink_repo_project(
+    client: &Postgrest,
+    github_repo_id: u64,
+    installation_id: u64,
+) -> Result<Option<String>, String> {
+    let response = client
+        .from("projects")
+        .eq("github_repo_id", github_repo_id.to_string())
+        .update(json!({ "installation_id": installation_id, "archived_at": null }).to_string())
+        .execute()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let text = response.text().await.map_err(|e| e.to_string())?;
+    let json: Value = serde_json::from_str(&text).map_err(|e| e.to_string())?;
+    Ok(json.get(0).is_some().then_some(text))
+}
+
+/// Keeps the project name in sync after a rename or transfer. The project
+/// stays linked through `github_repo_id`, which never changes.
+pub async fn rename_repo_project(
+    client: &Postgrest,
+    github_repo_id: u64,
+    name: &str,
+) -> Result<String, String> {
+    let response = client
+        .from("projects")
+        .eq("github_repo_id", github_repo_id.to_string())
+        .update(json!({ "name": name }).to_string())
+        .execute()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    response.text().await.map_err(|e| e.to_string())
+}
+
+/// Archives the projects of repositories the app lost access to. Their
+/// builds and deployments are kept.
+pub async fn archive_repo_projects(
+    client: &Postgrest,
+    github_repo_ids: &[u64],
+) -> Result<String, String> {
+    let ids: Vec<String> = github_repo_ids.iter().map(|id| id.to_string()).collect();
+
+    let response = client
+        .from("projects")
+        .in_("github_repo_id", ids)
+        .update(json!({ "archived_at": chrono::Utc::now().to_rfc3339() }).to_string())
+        .execute()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    response.text().await.map_err(|e| e.to_string())
+}
+
+pub async fn archive_installation_projects(
+    client: &Postgrest,
+    installation_id: u64,
+) -> Result<String, String> {
+    let response = client
+        .from("projects")
+        .eq("installation_id", installation_id.to_string())
+        .update(json!({ "archived_at": chrono::Utc::now().to_rfc3339() }).to_string())
+        .execute()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    response.text().await.map_err(|e| e.to_string())
 }
 
 pub async fn get_function_id(
@@ -169,3 +311,359 @@ pub async fn get_function_id(
         None => Err("Function ID not found".to_string()),
     }
 }
+
+pub async fn insert_build_job(
+    client: &Postgrest,
+    job_id: &str,
+    payload: &Value,
+) -> Result<String, String> {
+    let body = json!([{
+        "id": job_id,
+        "status": "queued",
+        "payload": payload,
+    }]);
+
+    let response = client
+        .from("build_jobs")
+        .insert(body.to_string())
+        .execute()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    response.text().await.map_err(|e| e.to_string())
+}
+
+pub async fn update_build_job(
+    client: &Postgrest,
+    job_id: &str,
+    fields: &Value,
+) -> Result<String, String> {
+    let mut body = fields.clone();
+    body["updated_at"] = json!(chrono::Utc::now().to_rfc3339());
+
+    let response = client
+        .from("build_jobs")
+        .eq("id", job_id)
+        .update(body.to_string())
+        .execute()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    response.text().await.map_err(|e| e.to_string())
+}
+
+pub async fn get_build_job(client: &Postgrest, job_id: &str) -> Result<Value, String> {
+    let response = client
+        .from("build_jobs")
+        .select("id,status,payload,check_run_id,function_check_run_ids,deployment_id")
+        .eq("id", job_id)
+        .execute()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let text = response.text().await.map_err(|e| e.to_string())?;
+    let json: Value = serde_json::from_str(&text).map_err(|e| e.to_string())?;
+
+    json.get(0)
+        .cloned()
+        .ok_or_else(|| format!("Build job {} not found", job_id))
+}
+
+/// Jobs that were still `queued` or `running` when the builder last stopped.
+pub async fn get_unfinished_build_jobs(client: &Postgrest) -> Result<Vec<Value>, String> {
+    let response = client
+        .from("build_jobs")
+        .select("id,status,payload,check_run_id,function_check_run_ids,deployment_id")
+        .in_("status", ["queued", "running"])
+        .order("created_at.asc")
+        .execute()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let text = response.text().await.map_err(|e| e.to_string())?;
+    let json: Value = serde_json::from_str(&text).map_err(|e| e.to_string())?;
+
+    match json {
+        Value::Array(jobs) => Ok(jobs),
+        other => Err(format!("Unexpected build_jobs response: {}", other)),
+    }
+}
+
+pub async fn upsert_preview_deployment(
+    client: &Postgrest,
+    project_id: &str,
+    function_id: &str,
+    build_id: &str,
+    pr_number: u64,
+    branch_name: &str,
+    s3_key: &str,
+) -> Result<String, String> {
+    let payload = json!([{
+        "project_id": project_id,
+        "function_id": function_id,
+        "project_build_id": build_id,
+        "pr_number": pr_number,
+        "branch_name": branch_name,
+        "s3_key": s3_key,
+    }]);
+
+    let response = client
+        .from("preview_deployments")
+        .upsert(payload.to_string())
+        .on_conflict("function_id,pr_number")
+        .execute()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    response.text().await.map_err(|e| e.to_string())
+}
+
+pub async fn get_preview_deployments(
+    client: &Postgrest,
+    project_id: &str,
+    pr_number: u64,
+) -> Result<Vec<Value>, String> {
+    let response = client
+        .from("preview_deployments")
+        .select("id,function_id,s3_key")
+        .eq("project_id", project_id)
+        .eq("pr_number", pr_number.to_string())
+        .execute()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let text = response.text().await.map_err(|e| e.to_string())?;
+    let json: Value = serde_json::from_str(&text).map_err(|e| e.to_string())?;
+
+    match json {
+        Value::Array(rows) => Ok(rows),
+        other => Err(format!("Unexpected preview_deployments response: {}", other)),
+    }
+}
+
+pub async fn delete_preview_deployments(
+    client: &Postgrest,
+    project_id: &str,
+    pr_number: u64,
+) -> Result<String, String> {
+    let response = client
+        .from("preview_deployments")
+        .eq("project_id", project_id)
+        .eq("pr_number", pr_number.to_string())
+        .delete()
+        .execute()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    response.text().await.map_err(|e| e.to_string())
+}
+
+/// Previews deployed from a branch, whichever pull request they belong to.
+pub async fn get_branch_preview_deployments(
+    client: &Postgrest,
+    project_id: &str,
+    branch_name: &str,
+) -> Result<Vec<Value>, String> {
+    let response = client
+        .from("preview_deployments")
+        .select("id,function_id,s3_key,pr_number")
+        .eq("project_id", project_id)
+        .eq("branch_name", branch_name)
+        .execute()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let text = response.text().await.map_err(|e| e.to_string())?;
+    let json: Value = serde_json::from_str(&text).map_err(|e| e.to_string())?;
+
+    match json {
+        Value::Array(rows) => Ok(rows),
+        other => Err(format!("Unexpected preview_deployments response: {}", other)),
+    }
+}
+
+pub async fn delete_branch_preview_deployments(
+    client: &Postgrest,
+    project_id: &str,
+    branch_name: &str,
+) -> Result<String, String> {
+    let response = client
+        .from("preview_deployments")
+        .eq("project_id", project_id)
+        .eq("branch_name", branch_name)
+        .delete()
+        .execute()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    response.text().await.map_err(|e| e.to_string())
+}
+
+/// Records `delivery_id`, returning `false` when it is already recorded at
+/// or after `since`. The unique `delivery_id` makes the insert the check, so
+/// concurrent redeliveries can't both get through; every record older than
+/// `since` is dropped first, so a delivery outside the window counts as new
+/// and the table only ever holds one window's worth.
+pub async fn claim_delivery(
+    client: &Postgrest,
+    delivery_id: &str,
+    event_type: &str,
+    since: &str,
+) -> Result<bool, String> {
+    client
+        .from("webhook_deliveries")
+        .lt("received_at", since)
+        .delete()
+        .execute()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let payload = json!([{
+        "delivery_id": delivery_id,
+        "event_type": event_type,
+        "received_at": chrono::Utc::now().to_rfc3339(),
+    }]);
+
+    let response = client
+        .from("webhook_deliveries")
+        .insert(payload.to_string())
+        .execute()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let status = response.status();
+    // 409: unique violation, the delivery is already recorded.
+    if status.as_u16() == 409 {
+        return Ok(false);
+    }
+    if !status.is_success() {
+        let text = response.text().await.unwrap_or_default();
+        return Err(format!("webhook_deliveries insert returned {}: {}", status, text));
+    }
+    Ok(true)
+}
+
+/// Forgets a delivery whose handling failed, so GitHub's redelivery of it
+/// is processed instead of dropped.
+pub async fn release_delivery(client: &Postgrest, delivery_id: &str) -> Result<String, String> {
+    let response = client
+        .from("webhook_deliveries")
+        .eq("delivery_id", delivery_id)
+        .delete()
+        .execute()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    response.text().await.map_err(|e| e.to_string())
+}
+
+/// The commit deployed on `branch_name` before `current_sha`: the most
+/// recent other commit with a successful function deployment whose build
+/// started before the first build of `current_sha`. Anchoring on the first
+/// build keeps a rolled back commit's rebuild from rolling "back" to the
+/// newer commit it replaced.
+pub async fn get_previous_deployed_commit(
+    client: &Postgrest,
+    project_id: &str,
+    branch_name: &str,
+    current_sha: &str,
+) -> Result<Option<String>, String> {
+    let response = client
+        .from("project_builds")
+        .select("created_at")
+        .eq("project_id", project_id)
+        .eq("branch_name", branch_name)
+        .eq("commit_sha", current_sha)
+        .order("created_at.asc")
+        .limit(1)
+        .execute()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let text = response.text().await.map_err(|e| e.to_string())?;
+    let json: Value = serde_json::from_str(&text).map_err(|e| e.to_string())?;
+    let Some(current_built_at) = json
+        .get(0)
+        .and_then(|entry| entry.get("created_at"))
+        .and_then(|at| at.as_str())
+    else {
+        return Ok(None);
+    };
+
+    let response = client
+        .from("project_builds")
+        .select("commit_sha,function_deployments!inner(status)")
+        .eq("project_id", project_id)
+        .eq("branch_name", branch_name)
+        .neq("commit_sha", current_sha)
+        .lt("created_at", current_built_at)
+        .eq("function_deployments.status", "success")
+        .order("created_at.desc")
+        .limit(1)
+        .execute()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let text = response.text().await.map_err(|e| e.to_string())?;
+    let json: Value = serde_json::from_str(&text).map_err(|e| e.to_string())?;
+
+    Ok(json
+        .get(0)
+        .and_then(|entry| entry.get("commit_sha"))
+        .and_then(|sha| sha.as_str())
+        .map(|sha| sha.to_string()))
+}
+
+/// Successful production deployments of a project from any branch, newest
+/// first, with the function name and the commit each was built from.
+pub async fn get_deployed_commits(
+    client: &Postgrest,
+    project_id: &str,
+) -> Result<Vec<Value>, String> {
+    let response = client
+        .from("function_deployments")
+        .select("functions!inner(name),project_builds!inner(project_id,commit_sha)")
+        .eq("project_builds.project_id", project_id)
+        .eq("status", "success")
+        .order("created_at.desc")
+        .limit(500)
+        .execute()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let text = response.text().await.map_err(|e| e.to_string())?;
+    let json: Value = serde_json::from_str(&text).map_err(|e| e.to_string())?;
+
+    match json {
+        Value::Array(rows) => Ok(rows),
+        other => Err(format!("Unexpected function_deployments response: {}", other)),
+    }
+}
+
+/// Successful deployments of a branch, newest first, with the function name
+/// and the sizes recorded for each.
+pub async fn get_branch_deployment_sizes(
+    client: &Postgrest,
+    project_id: &str,
+    branch_name: &str,
+) -> Result<Vec<Value>, String> {
+    let response = client
+        .from("function_deployments")
+        .select("wasm_size,compressed_size,functions!inner(name),project_builds!inner(project_id,branch_name)")
+        .eq("project_builds.project_id", project_id)
+        .eq("project_builds.branch_name", branch_name)
+        .eq("status", "success")
+        .order("created_at.desc")
+        .limit(500)
+        .execute()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let text = response.text().await.map_err(|e| e.to_string())?;
+    let json: Value = serde_json::from_str(&text).map_err(|e| e.to_string())?;
+
+    match json {
+        Value::Array(rows) => Ok(rows),
+        other => Err(format!("Unexpected function_deployments response: {}", other)),
+    }
+}